@@ -17,38 +17,39 @@
  *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::argparse::Opts;
+use crate::argparse::Mode;
 use crate::reporting::ReportMessage;
 //#[allow(unused)]
 //use log::{debug, error, info, trace, warn};
 use color_eyre::Result;
-use parsing::{generate_target_lists, InputLists};
+use parsing::{generate_target_lists, Target};
+use ratelimit::RateLimiter;
+use scheduler::Scheduler;
 use simplelog::{
     ColorChoice, CombinedLogger, Config, LevelFilter, SharedLogger, TermLogger,
     TerminalMode, WriteLogger,
 };
+use std::collections::HashSet;
 use std::fs::{create_dir_all, File};
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc};
 use std::thread;
-use web::chrome_worker;
 
 //#[macro_use]
 mod log_macros;
 
 mod argparse;
 mod parsing;
+mod ratelimit;
 mod rdp;
 mod reporting;
+mod scheduler;
+mod socket;
 mod util;
 mod vnc;
 mod web;
 
-pub enum ThreadStatus {
-    Complete,
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
     println!("Starting NCC Group Scrying...");
@@ -114,22 +115,42 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    // When resuming, leave out the targets that the previous run already
+    // tried, unless they failed and should be tried again. Their results
+    // are passed on to the report.
+    let previous = if opts.resume {
+        reporting::load_state(&opts.output_dir)?
+    } else {
+        Vec::new()
+    };
+    let done: HashSet<(String, String)> = previous
+        .iter()
+        .filter(|r| r.succeeded() || !opts.retry_failed)
+        .map(|r| (r.mode.clone(), r.target.clone()))
+        .collect();
+    let pending = Arc::new(targets.filtered(|mode, target| {
+        !done.contains(&(mode.to_string(), target.to_string()))
+    }));
+    if opts.resume {
+        log::info!("Skipping {} targets from the previous run", done.len());
+    }
+
     // Create output directories if they do not exist
     let output_base = Path::new(&opts.output_dir);
     let rdp_output_dir = output_base.join("rdp");
-    if !targets.rdp_targets.is_empty() && !rdp_output_dir.is_dir() {
+    if !pending.rdp_targets.is_empty() && !rdp_output_dir.is_dir() {
         create_dir_all(&rdp_output_dir).unwrap_or_else(|_| {
             panic!("Error creating directory {}", rdp_output_dir.display())
         });
     }
     let web_output_dir = output_base.join("web");
-    if !targets.web_targets.is_empty() && !web_output_dir.is_dir() {
+    if !pending.web_targets.is_empty() && !web_output_dir.is_dir() {
         create_dir_all(&web_output_dir).unwrap_or_else(|_| {
             panic!("Error creating directory {}", web_output_dir.display())
         });
     }
     let vnc_output_dir = output_base.join("vnc");
-    if !targets.vnc_targets.is_empty() && !vnc_output_dir.is_dir() {
+    if !pending.vnc_targets.is_empty() && !vnc_output_dir.is_dir() {
         create_dir_all(&vnc_output_dir).unwrap_or_else(|_| {
             panic!("Error creating directory {}", vnc_output_dir.display())
         });
@@ -154,185 +175,105 @@ async fn main() -> Result<()> {
         mpsc::Receiver<_>,
     ) = mpsc::channel();
     let opts_clone = opts.clone();
-    let targets_clone = targets.clone();
     let reporting_handle = thread::spawn(move || {
         log::debug!("Starting report thread");
-        reporting::reporting_thread(report_rx, opts_clone, targets_clone)
+        reporting::reporting_thread(report_rx, opts_clone, targets, previous)
     });
 
-    // Spawn threads to iterate over the targets
-    let rdp_handle = if !targets.rdp_targets.is_empty() {
-        let targets_clone = targets.clone();
-        let opts_clone = opts.clone();
-        let report_tx_clone = report_tx.clone();
-        let caught_ctrl_c_clone = caught_ctrl_c.clone();
-        Some(thread::spawn(move || {
-            log::debug!("Starting RDP worker threads");
-            rdp_worker(
-                targets_clone,
-                opts_clone,
-                report_tx_clone,
-                caught_ctrl_c_clone,
-            )
-        }))
-    } else {
-        None
-    };
-
-    let vnc_handle = if !targets.vnc_targets.is_empty() {
-        let targets_clone = targets.clone();
-        let opts_clone = opts.clone();
-        let report_tx_clone = report_tx.clone();
-        let caught_ctrl_c_clone = caught_ctrl_c.clone();
-        Some(thread::spawn(move || {
-            log::debug!("Starting VNC worker threads");
-            vnc_worker(
-                targets_clone,
-                opts_clone,
-                report_tx_clone,
-                caught_ctrl_c_clone,
-            )
-            .unwrap()
-        }))
+    // If there are any web targets then start Chrome. Its event handler
+    // runs on the async runtime, which the worker threads also use to
+    // drive the page captures.
+    let browser = if !pending.web_targets.is_empty() {
+        log::debug!("Starting Chrome");
+        match web::launch(&pending, &opts).await {
+            Ok(browser) => Some(Arc::new(browser)),
+            // Leave the RDP and VNC targets to run without it
+            Err(e) => {
+                log::error!(
+                    "Unable to start Chrome, skipping web targets: {e}"
+                );
+                None
+            }
+        }
     } else {
         None
     };
 
-    // If there are any web targets then start the web GUI process -
-    // due to limitations in the general design of GUI frameworks the
-    // GUI will either error or silently do nothing if not invoked from
-    // the main thread.
-    if !targets.web_targets.is_empty() {
-        let opts_clone = opts.clone();
-        let report_tx_clone = report_tx.clone();
-
-        log::debug!("Starting Web worker");
-        chrome_worker(targets, opts_clone, report_tx_clone, caught_ctrl_c)
-            .await
-            .unwrap();
-    }
+    // Queue the targets on a shared pool of worker threads, with up to
+    // opts.threads captures of each type at once
+    let protocols = [
+        &pending.rdp_targets,
+        &pending.vnc_targets,
+        &pending.web_targets,
+    ]
+    .iter()
+    .filter(|t| !t.is_empty())
+    .count();
+    let scheduler =
+        Scheduler::new(opts.threads * protocols, caught_ctrl_c.clone());
 
-    // wait for the workers to complete
-    if let Some(h) = rdp_handle {
-        h.join().unwrap().unwrap();
-    }
-    if let Some(h) = vnc_handle {
-        h.join().unwrap();
+    // Connection rate and per-host limits apply to every target type
+    let limiter = Arc::new(RateLimiter::new(&opts));
+    scheduler.limit_per_host(opts.max_per_host);
+    scheduler.limit(Mode::Rdp, opts.threads);
+    scheduler.limit(Mode::Vnc, opts.threads);
+    scheduler.limit(Mode::Web, opts.threads);
+    // Credentials from target files override those on the command line
+    let target_opts =
+        |target: &Target| match pending.credentials.get(&target.to_string()) {
+            Some(credentials) => Arc::new(credentials.apply(&opts)),
+            None => opts.clone(),
+        };
+    for target in &pending.rdp_targets {
+        let target = target.clone();
+        let opts = target_opts(&target);
+        let report_tx = report_tx.clone();
+        let limiter = limiter.clone();
+        scheduler.submit(Mode::Rdp, target.host(), move || {
+            info!("RDP", "Starting capture of {}", target);
+            rdp::capture(&target, &opts, &report_tx, &limiter)
+        });
     }
-    report_tx.send(ReportMessage::GenerateReport).unwrap();
-    reporting_handle.join().unwrap().unwrap();
-
-    Ok(())
-}
-
-fn rdp_worker(
-    targets: Arc<InputLists>,
-    opts: Arc<Opts>,
-    report_tx: mpsc::Sender<ReportMessage>,
-    caught_ctrl_c: Arc<AtomicBool>,
-) -> Result<()> {
-    use mpsc::{Receiver, Sender};
-    let max_workers = opts.threads;
-    let mut num_workers: usize = 0;
-    let mut targets_iter = targets.rdp_targets.iter();
-    let mut workers: Vec<_> = Vec::new();
-    let (thread_status_tx, thread_status_rx): (
-        Sender<ThreadStatus>,
-        Receiver<ThreadStatus>,
-    ) = mpsc::channel();
-    while !caught_ctrl_c.load(Ordering::SeqCst) {
-        // check for status messages
-        // Turn off clippy's single_match warning here because match
-        // matches the intuition for how try_recv is processed better
-        // than an if let.
-        #[allow(clippy::single_match)]
-        match thread_status_rx.try_recv() {
-            Ok(ThreadStatus::Complete) => {
-                debug!("RDP", "Thread complete, yay");
-                num_workers -= 1;
-            }
-            Err(_) => {}
-        }
-        if num_workers < max_workers {
-            if let Some(target) = targets_iter.next() {
-                let target = target.clone();
-                info!("RDP", "Adding worker for {:?}", target);
-                let opts_clone = opts.clone();
-                let tx = thread_status_tx.clone();
-                let report_tx_clone = report_tx.clone();
-                let handle = thread::spawn(move || {
-                    rdp::capture(&target, &opts_clone, tx, &report_tx_clone)
-                });
-
-                workers.push(handle);
-                num_workers += 1;
-            } else {
-                break;
-            }
-        }
+    for target in &pending.vnc_targets {
+        let target = target.clone();
+        let opts = target_opts(&target);
+        let report_tx = report_tx.clone();
+        let limiter = limiter.clone();
+        scheduler.submit(Mode::Vnc, target.host(), move || {
+            info!("VNC", "Starting capture of {}", target);
+            vnc::capture(&target, &opts, &report_tx, &limiter)
+        });
     }
-    debug!("RDP", "At the join part");
-    for w in workers {
-        debug!("RDP", "Joining {:?}", w);
-        if w.join().is_err() {
-            debug!("RDP", "Thread finished with errors");
+    if let Some(browser) = &browser {
+        let runtime = tokio::runtime::Handle::current();
+        for target in &pending.web_targets {
+            let target = target.clone();
+            let opts = opts.clone();
+            let report_tx = report_tx.clone();
+            let limiter = limiter.clone();
+            let browser = browser.clone();
+            let runtime = runtime.clone();
+            // Virtual hosts count towards the limit of the address that
+            // they are mapped to
+            let host = target.host();
+            let host = match pending.hostnames.get(&host) {
+                Some(addr) => addr.to_string(),
+                None => host,
+            };
+            scheduler.submit(Mode::Web, host, move || {
+                info!("Web", "Starting capture of {}", target);
+                web::capture(
+                    &runtime, &browser, &target, &opts, &report_tx, &limiter,
+                )
+            });
         }
     }
 
-    Ok(())
-}
-
-fn vnc_worker(
-    targets: Arc<InputLists>,
-    opts: Arc<Opts>,
-    report_tx: mpsc::Sender<ReportMessage>,
-    caught_ctrl_c: Arc<AtomicBool>,
-) -> Result<()> {
-    use mpsc::{Receiver, Sender};
-    let max_workers = opts.threads;
-    let mut num_workers: usize = 0;
-    let mut targets_iter = targets.vnc_targets.iter();
-    let mut workers: Vec<_> = Vec::new();
-    let (thread_status_tx, thread_status_rx): (
-        Sender<ThreadStatus>,
-        Receiver<ThreadStatus>,
-    ) = mpsc::channel();
-    while !caught_ctrl_c.load(Ordering::SeqCst) {
-        // check for status messages
-        // Turn off clippy's single_match warning here because match
-        // matches the intuition for how try_recv is processed better
-        // than an if let.
-        #[allow(clippy::single_match)]
-        match thread_status_rx.try_recv() {
-            Ok(ThreadStatus::Complete) => {
-                info!("VNC", "Thread complete, yay");
-                num_workers -= 1;
-            }
-            Err(_) => {}
-        }
-        if num_workers < max_workers {
-            if let Some(target) = targets_iter.next() {
-                let target = target.clone();
-                info!("VNC", "Adding worker for {:?}", target);
-                let opts_clone = opts.clone();
-                let tx = thread_status_tx.clone();
-                let report_tx_clone = report_tx.clone();
-                let handle = thread::spawn(move || {
-                    vnc::capture(&target, &opts_clone, tx, &report_tx_clone)
-                });
-
-                workers.push(handle);
-                num_workers += 1;
-            } else {
-                break;
-            }
-        }
-    }
-    debug!("VNC", "At the join part");
-    for w in workers {
-        debug!("VNC", "Joining {:?}", w);
-        w.join().unwrap();
-    }
+    // wait for the workers to complete, without holding up the async
+    // runtime that Chrome's event handler runs on
+    tokio::task::block_in_place(|| scheduler.wait());
+    report_tx.send(ReportMessage::GenerateReport).unwrap();
+    reporting_handle.join().unwrap().unwrap();
 
     Ok(())
 }
@@ -0,0 +1,222 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020-2021 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Import of the line-based "greppable" output formats of Nmap (-oG)
+//! and RustScan (-g). Nmap's format includes service names when they
+//! are known, whereas RustScan only lists the open ports of each host.
+
+use super::{lists_from_service, InputLists, Service};
+use crate::argparse::Opts;
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use std::io::BufRead;
+use std::net::IpAddr;
+
+/// A port from a "Ports:" field of Nmap's greppable output
+#[derive(Debug, Eq, PartialEq)]
+struct GreppablePort<'a> {
+    port: u16,
+    state: &'a str,
+    protocol: &'a str,
+    service: &'a str,
+    version: &'a str,
+}
+
+/// Parse a port entry such as "80/open/tcp//http//Apache httpd 2.4.41/".
+/// The fields are port, state, protocol, owner, service, RPC info and
+/// version.
+fn parse_port(entry: &str) -> Option<GreppablePort<'_>> {
+    let fields: Vec<&str> = entry.trim().split('/').collect();
+    match fields.as_slice() {
+        [port, state, protocol, _owner, service, _rpc, version, ..] => {
+            Some(GreppablePort {
+                port: port.parse().ok()?,
+                state,
+                protocol,
+                service,
+                version,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Split the contents of a "Ports:" field into port entries. Entries
+/// are separated by ", ", but version strings may contain it too, so
+/// a new entry only starts where a port number follows.
+fn split_ports(ports: &str) -> Vec<String> {
+    let mut entries: Vec<String> = Vec::new();
+    for piece in ports.split(", ") {
+        let starts_entry = piece
+            .split('/')
+            .next()
+            .and_then(|p| p.parse::<u16>().ok())
+            .is_some();
+        match entries.last_mut() {
+            Some(entry) if !starts_entry => {
+                entry.push_str(", ");
+                entry.push_str(piece);
+            }
+            _ => entries.push(piece.to_string()),
+        }
+    }
+    entries
+}
+
+/// Read the targets from an Nmap greppable file. Open TCP ports are
+/// used, and open|filtered ones with `--nmap-open-filtered`. Services
+/// with version information were found by probing them.
+pub fn lists_from_nmap_grep(
+    reader: &mut dyn BufRead,
+    opts: &Opts,
+) -> Result<InputLists, String> {
+    let mut list: InputLists = Default::default();
+    for line in reader.lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.starts_with('#') {
+            continue;
+        }
+        let mut host = None;
+        let mut ports = None;
+        for field in line.split('\t') {
+            if let Some(h) = field.strip_prefix("Host: ") {
+                host = h.split_whitespace().next();
+            } else if let Some(p) = field.strip_prefix("Ports: ") {
+                ports = Some(p);
+            }
+        }
+        let (host, ports) = match (host, ports) {
+            (Some(host), Some(ports)) => (host, ports),
+            _ => continue,
+        };
+        let addr: IpAddr = match host.parse() {
+            Ok(addr) => addr,
+            Err(e) => {
+                warn!("Error parsing host {:?}: {}", host, e);
+                continue;
+            }
+        };
+
+        for entry in split_ports(ports) {
+            let port = match parse_port(&entry) {
+                Some(port) => port,
+                None => {
+                    warn!("Error parsing port {:?}", entry);
+                    continue;
+                }
+            };
+            let open = port.state == "open"
+                || (port.state == "open|filtered" && opts.nmap_open_filtered);
+            if port.protocol != "tcp" || !open {
+                trace!("Skipping {:?}", port);
+                continue;
+            }
+            let service = Service {
+                port: port.port,
+                name: port.service,
+                product: port.version,
+                tunnel_ssl: false,
+                probed: !port.version.is_empty(),
+            };
+            lists_from_service(addr, &service, opts, &mut list);
+        }
+    }
+    Ok(list)
+}
+
+/// Read the targets from a RustScan greppable file, which has a line
+/// such as "192.0.2.1 -> [22,80,443]" for each host
+pub fn lists_from_rustscan(
+    reader: &mut dyn BufRead,
+    opts: &Opts,
+) -> Result<InputLists, String> {
+    let mut list: InputLists = Default::default();
+    for line in reader.lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        let (host, ports) = match line.split_once("->") {
+            Some(split) => split,
+            None => continue,
+        };
+        let addr: IpAddr = match host.trim().parse() {
+            Ok(addr) => addr,
+            Err(e) => {
+                warn!("Error parsing host {:?}: {}", host, e);
+                continue;
+            }
+        };
+        let ports = ports.trim().trim_start_matches('[').trim_end_matches(']');
+        for port in ports.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match port.parse() {
+                Ok(port) => lists_from_service(
+                    addr,
+                    &Service::from_port(port),
+                    opts,
+                    &mut list,
+                ),
+                Err(e) => warn!("Error parsing port {:?}: {}", port, e),
+            }
+        }
+    }
+    Ok(list)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn greppable_ports() {
+        let ports = concat!(
+            "22/open/tcp//ssh//OpenSSH 8.2p1, protocol 2.0/,",
+            " 443/open/tcp//ssl|http//Apache httpd 2.4.41/,",
+            " 161/open|filtered/udp//snmp///"
+        );
+        let expected = vec![
+            GreppablePort {
+                port: 22,
+                state: "open",
+                protocol: "tcp",
+                service: "ssh",
+                version: "OpenSSH 8.2p1, protocol 2.0",
+            },
+            GreppablePort {
+                port: 443,
+                state: "open",
+                protocol: "tcp",
+                service: "ssl|http",
+                version: "Apache httpd 2.4.41",
+            },
+            GreppablePort {
+                port: 161,
+                state: "open|filtered",
+                protocol: "udp",
+                service: "snmp",
+                version: "",
+            },
+        ];
+
+        let entries = split_ports(ports);
+        for (entry, expected) in entries.iter().zip(&expected) {
+            eprintln!("Test case: {:?}", entry);
+            assert_eq!(parse_port(entry).as_ref(), Some(expected));
+        }
+        assert_eq!(entries.len(), expected.len());
+        assert_eq!(parse_port("80/open/tcp"), None);
+    }
+}
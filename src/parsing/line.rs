@@ -0,0 +1,171 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020-2021 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Parsing of the lines of target files. Each line holds a single
+//! target, optionally preceded by the type of target and followed by
+//! credentials and tags:
+//!
+//! ```text
+//! # Comments start with a hash
+//! 192.0.2.1
+//! rdp 192.0.2.2 user=admin pass="correct horse" domain=CORP
+//! vnc 192.0.2.3:5901 pass=secret tag=lab
+//! web 192.0.2.4:8443 tag=dmz tag=prod  # so can the ends of lines
+//! ```
+
+use super::Credentials;
+use crate::argparse::Mode;
+
+/// A target from a line of a target file
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Line {
+    /// The type of target given at the start of the line, if any
+    pub mode: Option<Mode>,
+    pub target: String,
+    pub credentials: Credentials,
+    pub tags: Vec<String>,
+}
+
+/// Split a line into words separated by whitespace. Double quotes
+/// group words containing spaces, and a word starting with # begins a
+/// comment that runs to the end of the line.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => {
+                words.extend(word.take());
+            }
+            '#' if word.is_none() => break,
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quote".to_string());
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Parse a line of a target file. Blank lines and comments give None.
+pub fn parse(line: &str) -> Result<Option<Line>, String> {
+    let mut words = split_words(line)?.into_iter().peekable();
+    let first = match words.next() {
+        Some(first) => first,
+        None => return Ok(None),
+    };
+
+    let mut parsed: Line = Default::default();
+    match first.to_lowercase().as_str() {
+        "rdp" | "vnc" | "web" if words.peek().is_some() => {
+            parsed.mode = first.to_lowercase().parse().ok();
+            parsed.target = words.next().unwrap_or_default();
+        }
+        _ => parsed.target = first,
+    }
+
+    for word in words {
+        let (key, value) = word
+            .split_once('=')
+            .ok_or(format!("Expected key=value after the target: {}", word))?;
+        let value = value.to_string();
+        match key {
+            "user" => parsed.credentials.user = Some(value),
+            "pass" | "password" => parsed.credentials.pass = Some(value),
+            "domain" => parsed.credentials.domain = Some(value),
+            "tag" => parsed.tags.push(value),
+            _ => return Err(format!("Unknown key: {}", key)),
+        }
+    }
+    Ok(Some(parsed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_lines() {
+        let target = |mode: Option<Mode>, target: &str| Line {
+            mode,
+            target: target.to_string(),
+            ..Default::default()
+        };
+        let test_cases = vec![
+            ("", None),
+            ("   ", None),
+            ("# a comment", None),
+            ("  #192.0.2.1", None),
+            ("192.0.2.1", Some(target(None, "192.0.2.1"))),
+            ("  192.0.2.1  # comment", Some(target(None, "192.0.2.1"))),
+            ("rdp 192.0.2.1", Some(target(Some(Mode::Rdp), "192.0.2.1"))),
+            (
+                "WEB 192.0.2.1:8443",
+                Some(target(Some(Mode::Web), "192.0.2.1:8443")),
+            ),
+            (
+                "http://192.0.2.1/#/app",
+                Some(target(None, "http://192.0.2.1/#/app")),
+            ),
+            // A hostname that happens to be a mode
+            ("web", Some(target(None, "web"))),
+            (
+                r#"rdp 192.0.2.2 user=admin pass="correct horse" domain=CORP"#,
+                Some(Line {
+                    mode: Some(Mode::Rdp),
+                    target: "192.0.2.2".to_string(),
+                    credentials: Credentials {
+                        user: Some("admin".to_string()),
+                        pass: Some("correct horse".to_string()),
+                        domain: Some("CORP".to_string()),
+                    },
+                    tags: Vec::new(),
+                }),
+            ),
+            (
+                "vnc 192.0.2.3:5901 password=#secret tag=lab tag=prod # lab",
+                Some(Line {
+                    mode: Some(Mode::Vnc),
+                    target: "192.0.2.3:5901".to_string(),
+                    credentials: Credentials {
+                        pass: Some("#secret".to_string()),
+                        ..Default::default()
+                    },
+                    tags: vec!["lab".to_string(), "prod".to_string()],
+                }),
+            ),
+        ];
+
+        for (line, expected) in test_cases {
+            eprintln!("Test case: {:?}", line);
+            assert_eq!(parse(line).unwrap(), expected);
+        }
+
+        for line in ["192.0.2.1 admin", "192.0.2.1 colour=red", r#"a pass=""#] {
+            eprintln!("Test case: {:?}", line);
+            assert!(parse(line).is_err());
+        }
+    }
+}
@@ -0,0 +1,180 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020-2021 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Import of masscan output, in either the JSON (-oJ) or list (-oL)
+//! format. Masscan only reports open ports, so targets are chosen by
+//! port number alone.
+
+use super::{lists_from_service, InputLists, Service};
+use crate::argparse::Opts;
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use serde::Deserialize;
+use std::io::BufRead;
+use std::net::IpAddr;
+
+#[derive(Debug, Deserialize)]
+struct MasscanHost {
+    ip: IpAddr,
+    #[serde(default)]
+    ports: Vec<MasscanPort>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MasscanPort {
+    port: u16,
+    proto: String,
+    /// Missing from the records that only carry a banner
+    status: Option<String>,
+}
+
+/// Parse one line of masscan's JSON output. Masscan writes one host per
+/// line, but the lines are separated by commas that different versions
+/// put at the start or end of the line, so the commas are removed and
+/// each line is parsed on its own. The surrounding brackets and the
+/// "{finished: 1}" marker are not hosts and return None.
+fn parse_json_line(line: &str) -> Option<Result<MasscanHost, String>> {
+    let line = line.trim().trim_matches(',').trim();
+    if !line.starts_with('{') || line.starts_with("{finished") {
+        return None;
+    }
+    Some(serde_json::from_str(line).map_err(|e| e.to_string()))
+}
+
+/// Parse one line of masscan's list output, e.g.
+/// "open tcp 80 192.0.2.1 1600000000". Comments, banners and closed
+/// ports return None.
+fn parse_list_line(line: &str) -> Option<Result<(IpAddr, u16), String>> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields.as_slice() {
+        ["open", "tcp", port, ip, ..] => Some(
+            port.parse()
+                .map_err(|e| format!("Invalid port {:?}: {}", port, e))
+                .and_then(|port| {
+                    ip.parse()
+                        .map(|ip| (ip, port))
+                        .map_err(|e| format!("Invalid address {:?}: {}", ip, e))
+                }),
+        ),
+        _ => None,
+    }
+}
+
+/// Read the targets from a masscan JSON or list file. The format is
+/// worked out from the first line that is not blank.
+pub fn lists_from_masscan(
+    reader: &mut dyn BufRead,
+    opts: &Opts,
+) -> Result<InputLists, String> {
+    let mut list: InputLists = Default::default();
+    let mut json = None;
+    for line in reader.lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        let json = *json.get_or_insert_with(|| {
+            trimmed.starts_with('[') || trimmed.starts_with('{')
+        });
+
+        if json {
+            match parse_json_line(&line) {
+                Some(Ok(host)) => {
+                    for port in host.ports {
+                        if port.proto != "tcp"
+                            || port.status.as_deref().unwrap_or("open")
+                                != "open"
+                        {
+                            trace!("Skipping {:?}", port);
+                            continue;
+                        }
+                        let service = Service::from_port(port.port);
+                        lists_from_service(host.ip, &service, opts, &mut list);
+                    }
+                }
+                Some(Err(e)) => warn!("Error parsing {:?}: {}", line, e),
+                None => {}
+            }
+        } else {
+            match parse_list_line(&line) {
+                Some(Ok((ip, port))) => {
+                    let service = Service::from_port(port);
+                    lists_from_service(ip, &service, opts, &mut list);
+                }
+                Some(Err(e)) => warn!("Error parsing {:?}: {}", line, e),
+                None => trace!("Skipping {:?}", line),
+            }
+        }
+    }
+    Ok(list)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn masscan_lines() {
+        let test_cases = vec![
+            ("[", None),
+            ("]", None),
+            ("{finished: 1}", None),
+            (
+                concat!(
+                    r#"{ "ip": "192.0.2.1", "timestamp": "1600000000","#,
+                    r#" "ports": [ {"port": 80, "proto": "tcp","#,
+                    r#" "status": "open", "reason": "syn-ack", "ttl": 64} ] },"#
+                ),
+                Some("192.0.2.1"),
+            ),
+            (
+                concat!(
+                    r#",{ "ip": "2001:db8::1", "ports": [ {"port": 443,"#,
+                    r#" "proto": "tcp", "service": {"name": "http"}} ] }"#
+                ),
+                Some("2001:db8::1"),
+            ),
+        ];
+        for (line, expected) in test_cases {
+            eprintln!("Test case: {:?}", line);
+            let ip = parse_json_line(line).map(|h| h.unwrap().ip.to_string());
+            assert_eq!(ip.as_deref(), expected);
+        }
+
+        let test_cases = vec![
+            ("#masscan", None),
+            ("open tcp 80 192.0.2.1 1600000000", Some(("192.0.2.1", 80))),
+            (
+                "open tcp 3389 2001:db8::1 1600000000",
+                Some(("2001:db8::1", 3389)),
+            ),
+            ("open udp 161 192.0.2.1 1600000000", None),
+            ("banner tcp 80 192.0.2.1 1600000000 http Apache", None),
+            ("# end", None),
+        ];
+        for (line, expected) in test_cases {
+            eprintln!("Test case: {:?}", line);
+            let expected =
+                expected.map(|(ip, port)| (ip.parse().unwrap(), port));
+            assert_eq!(parse_list_line(line).map(Result::unwrap), expected);
+        }
+        assert!(parse_list_line("open tcp http 192.0.2.1").unwrap().is_err());
+    }
+}
@@ -21,13 +21,24 @@ use crate::argparse::{Mode, Opts};
 #[allow(unused)]
 use log::{debug, error, info, trace, warn};
 use nessus_xml_parser::NessusScan;
-use nmap_xml_parser::{port::PortState, NmapResults};
+use nmap_xml_parser::host::HostState;
+use nmap_xml_parser::port::{PortProtocol, PortState};
+use nmap_xml_parser::NmapResults;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Display;
 use std::fs::{self, File};
 use std::io::{self, prelude::*, BufReader};
 use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use url::Url;
 
+mod greppable;
+mod line;
+mod masscan;
+mod msf;
+mod range;
+pub mod scope;
+mod urls;
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Target {
     Address(SocketAddr),
@@ -37,11 +48,52 @@ pub enum Target {
 
 // InputLists moved above the impl on Target because the impl is
 // pretty long
-#[derive(Default, Debug, Eq, PartialEq, PartialOrd)]
+#[derive(Clone, Default, Debug, Eq, PartialEq, PartialOrd)]
 pub struct InputLists {
     pub rdp_targets: Vec<Target>,
     pub web_targets: Vec<Target>,
     pub vnc_targets: Vec<Target>,
+    /// The input that each target was first loaded from, keyed by the
+    /// target's string representation
+    pub sources: BTreeMap<String, String>,
+    /// Hostnames from scan files mapped to the address that was scanned,
+    /// so that web targets using the hostname still reach the same host
+    pub hostnames: BTreeMap<String, IpAddr>,
+    /// Credentials given for RDP and VNC targets in target files, keyed
+    /// by the target's string representation
+    pub credentials: BTreeMap<String, Credentials>,
+    /// Tags given for targets in target files, keyed by the target's
+    /// string representation
+    pub tags: BTreeMap<String, Vec<String>>,
+    /// Targets removed by the scope or exclude lists, with the reason
+    pub excluded: BTreeMap<String, String>,
+}
+
+/// Credentials for a single target, overriding those given on the
+/// command line
+#[derive(Clone, Debug, Default, Eq, PartialEq, PartialOrd)]
+pub struct Credentials {
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    pub domain: Option<String>,
+}
+
+impl Credentials {
+    fn is_empty(&self) -> bool {
+        self.user.is_none() && self.pass.is_none() && self.domain.is_none()
+    }
+
+    /// Copy of the options with these credentials used for RDP and, in
+    /// the case of the password, VNC
+    pub fn apply(&self, opts: &Opts) -> Opts {
+        Opts {
+            rdp_user: self.user.clone().or_else(|| opts.rdp_user.clone()),
+            rdp_pass: self.pass.clone().or_else(|| opts.rdp_pass.clone()),
+            rdp_domain: self.domain.clone().or_else(|| opts.rdp_domain.clone()),
+            vnc_auth: self.pass.clone().or_else(|| opts.vnc_auth.clone()),
+            ..opts.clone()
+        }
+    }
 }
 
 impl InputLists {
@@ -49,6 +101,252 @@ impl InputLists {
         self.rdp_targets.append(&mut list.rdp_targets);
         self.web_targets.append(&mut list.web_targets);
         self.vnc_targets.append(&mut list.vnc_targets);
+        for (target, source) in std::mem::take(&mut list.sources) {
+            self.sources.entry(target).or_insert(source);
+        }
+        for (name, addr) in std::mem::take(&mut list.hostnames) {
+            self.record_hostname(&name, addr);
+        }
+        for (target, credentials) in std::mem::take(&mut list.credentials) {
+            self.credentials.entry(target).or_insert(credentials);
+        }
+        for (target, mut tags) in std::mem::take(&mut list.tags) {
+            let existing = self.tags.entry(target).or_default();
+            tags.retain(|t| !existing.contains(t));
+            existing.append(&mut tags);
+        }
+    }
+
+    /// Record the address that a hostname from a scan file was found at.
+    /// Chrome can only be pointed at one address for a name, so a name
+    /// found at a second address keeps the first.
+    fn record_hostname(&mut self, name: &str, addr: IpAddr) {
+        match self.hostnames.get(name) {
+            Some(first) if *first != addr => warn!(
+                "{} was found at both {} and {}, only {} will be captured",
+                name, first, addr, first
+            ),
+            Some(_) => {}
+            None => {
+                self.hostnames.insert(name.to_string(), addr);
+            }
+        }
+    }
+
+    /// Record the credentials and tags from a line of a target file
+    /// against each of the targets in the lists. Credentials are only
+    /// used for RDP and VNC.
+    fn record_line_details(&mut self, line: &line::Line) {
+        if !line.credentials.is_empty() {
+            for t in self.rdp_targets.iter().chain(&self.vnc_targets) {
+                self.credentials
+                    .insert(t.to_string(), line.credentials.clone());
+            }
+        }
+        if !line.tags.is_empty() {
+            for t in self
+                .rdp_targets
+                .iter()
+                .chain(&self.web_targets)
+                .chain(&self.vnc_targets)
+            {
+                self.tags.insert(t.to_string(), line.tags.clone());
+            }
+        }
+    }
+
+    /// The types of target in the lists, e.g. "RDP and VNC"
+    fn types(&self) -> String {
+        let types: Vec<&str> = [
+            ("RDP", &self.rdp_targets),
+            ("Web", &self.web_targets),
+            ("VNC", &self.vnc_targets),
+        ]
+        .iter()
+        .filter(|(_, targets)| !targets.is_empty())
+        .map(|(name, _)| *name)
+        .collect();
+        types.join(" and ")
+    }
+
+    /// Record the source of any targets that do not have one yet. This
+    /// is called after each input has been processed.
+    fn record_source(&mut self, source: &str) {
+        for t in self
+            .rdp_targets
+            .iter()
+            .chain(&self.web_targets)
+            .chain(&self.vnc_targets)
+        {
+            self.sources
+                .entry(t.to_string())
+                .or_insert_with(|| source.to_string());
+        }
+    }
+
+    /// Copy of the lists containing only the targets for which `keep`
+    /// returns true, e.g. those not already captured by a previous run
+    pub fn filtered(&self, keep: impl Fn(Mode, &Target) -> bool) -> Self {
+        let filter = |mode, targets: &[Target]| -> Vec<Target> {
+            targets.iter().filter(|t| keep(mode, t)).cloned().collect()
+        };
+        let mut lists = Self {
+            rdp_targets: filter(Mode::Rdp, &self.rdp_targets),
+            web_targets: filter(Mode::Web, &self.web_targets),
+            vnc_targets: filter(Mode::Vnc, &self.vnc_targets),
+            sources: BTreeMap::new(),
+            hostnames: self.hostnames.clone(),
+            credentials: BTreeMap::new(),
+            tags: BTreeMap::new(),
+            excluded: self.excluded.clone(),
+        };
+        for t in lists
+            .rdp_targets
+            .iter()
+            .chain(&lists.web_targets)
+            .chain(&lists.vnc_targets)
+        {
+            let target = t.to_string();
+            if let Some(source) = self.sources.get(&target) {
+                lists.sources.insert(target.clone(), source.clone());
+            }
+            if let Some(credentials) = self.credentials.get(&target) {
+                lists
+                    .credentials
+                    .insert(target.clone(), credentials.clone());
+            }
+            if let Some(tags) = self.tags.get(&target) {
+                lists.tags.insert(target, tags.clone());
+            }
+        }
+        lists
+    }
+
+    /// Web targets that are the same service reached by hostname and by
+    /// IP address, keyed by target. Each target maps to the others in
+    /// its group so that the report can link them together.
+    pub fn related_targets(&self) -> BTreeMap<String, Vec<String>> {
+        let web_targets: BTreeSet<String> =
+            self.web_targets.iter().map(|t| t.to_string()).collect();
+        let mut groups: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for t in &self.web_targets {
+            let u = match t {
+                Target::Url(u) => u,
+                Target::Address(_) => continue,
+            };
+            let addr = match u.host_str().and_then(|h| self.hostnames.get(h)) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let mut by_addr = u.clone();
+            if by_addr.set_ip_host(*addr).is_err() {
+                continue;
+            }
+            let by_addr = by_addr.to_string();
+            if web_targets.contains(&by_addr) {
+                let group = groups.entry(by_addr.clone()).or_default();
+                group.insert(by_addr);
+                group.insert(u.to_string());
+            }
+        }
+
+        let mut related = BTreeMap::new();
+        for group in groups.values() {
+            for target in group {
+                related.insert(
+                    target.clone(),
+                    group.iter().filter(|&t| t != target).cloned().collect(),
+                );
+            }
+        }
+        related
+    }
+
+    /// Remove the targets for which `reason` returns a reason for
+    /// excluding them, recording them in `excluded`
+    fn exclude(&mut self, mut reason: impl FnMut(&Target) -> Option<String>) {
+        let excluded = &mut self.excluded;
+        for targets in [
+            &mut self.rdp_targets,
+            &mut self.web_targets,
+            &mut self.vnc_targets,
+        ] {
+            targets.retain(|t| match reason(t) {
+                Some(r) => {
+                    info!("Excluding {}: {}", t, r);
+                    excluded.insert(t.to_string(), r);
+                    false
+                }
+                None => true,
+            });
+        }
+    }
+
+    /// Apply the scope and exclude lists from the options. Targets
+    /// using a hostname are also checked by the addresses they connect
+    /// to: the scanned address for hostnames from scan files, otherwise
+    /// every address the name resolves to. If either list cannot be
+    /// loaded then every target is excluded rather than risk capturing
+    /// something out of scope.
+    fn apply_scope(&mut self, opts: &Opts) {
+        if opts.scope.is_empty() && opts.exclude.is_empty() {
+            return;
+        }
+        let lists = scope::Scope::load(&opts.scope)
+            .and_then(|s| Ok((s, scope::Scope::load(&opts.exclude)?)));
+        let (scope, exclude) = match lists {
+            Ok(lists) => lists,
+            Err(e) => {
+                error!("Failed to load scope: {}", e);
+                self.exclude(|_| Some("scope could not be loaded".into()));
+                return;
+            }
+        };
+
+        let resolve = scope.has_addresses() || exclude.has_addresses();
+        let hostnames = self.hostnames.clone();
+        let mut resolved: BTreeMap<String, Vec<IpAddr>> = BTreeMap::new();
+        self.exclude(|t| {
+            let host = t.host();
+            let addrs: &[IpAddr] = if host.parse::<IpAddr>().is_ok() || !resolve
+            {
+                &[]
+            } else if let Some(addr) = hostnames.get(&host) {
+                std::slice::from_ref(addr)
+            } else {
+                resolved.entry(host.clone()).or_insert_with(|| {
+                    match (host.as_str(), 0).to_socket_addrs() {
+                        Ok(addrs) => addrs.map(|a| a.ip()).collect(),
+                        Err(e) => {
+                            debug!("Unable to resolve {}: {}", host, e);
+                            Vec::new()
+                        }
+                    }
+                })
+            };
+
+            if let Some(entry) = exclude.find(t, addrs) {
+                Some(format!("excluded by {}", entry))
+            } else if addrs.is_empty()
+                && exclude.has_addresses()
+                && host.parse::<IpAddr>().is_err()
+            {
+                Some(format!(
+                    "{} could not be resolved to check the exclude list",
+                    host
+                ))
+            } else if !scope.is_empty() && !scope.contains(t, addrs) {
+                Some("not in scope".to_string())
+            } else {
+                None
+            }
+        });
+        if !self.excluded.is_empty() {
+            warn!(
+                "{} targets excluded by the scope and exclude lists",
+                self.excluded.len()
+            );
+        }
     }
 
     fn dedup(&mut self) {
@@ -239,6 +537,22 @@ impl<'a> Target {
     }
 }
 
+impl Target {
+    /// The host part of the target, without brackets around IPv6
+    /// addresses, for grouping targets on the same host
+    pub fn host(&self) -> String {
+        use url::Host;
+        match self {
+            Target::Address(addr) => addr.ip().to_string(),
+            Target::Url(u) => match u.host() {
+                Some(Host::Ipv6(a)) => a.to_string(),
+                Some(h) => h.to_string(),
+                None => String::new(),
+            },
+        }
+    }
+}
+
 impl Display for Target {
     fn fmt(
         &self,
@@ -353,182 +667,151 @@ fn ip_port_to_sockaddr(input: &str) -> Result<SocketAddr, io::Error> {
     ))
 }
 
+/// Expand CIDR blocks and address ranges into individual addresses.
+/// Inputs that are not ranges are passed through unchanged, and ranges
+/// that cannot be expanded result in an empty list.
+fn expand_input(input: &str, opts: &Opts) -> Vec<String> {
+    match range::expand(input, opts.range_limit) {
+        None => vec![input.to_string()],
+        Some(Ok(addrs)) => {
+            info!("Expanded {} into {} addresses", input, addrs.len());
+            addrs.iter().map(ToString::to_string).collect()
+        }
+        Some(Err(e)) => {
+            warn!("Refusing to expand {}: {}", input, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Parse a target as each of the types of target that the mode allows
+fn parse_target(input: &str, mode: Mode) -> InputLists {
+    let mut lists: InputLists = Default::default();
+    for (m, targets) in [
+        (Mode::Rdp, &mut lists.rdp_targets),
+        (Mode::Web, &mut lists.web_targets),
+        (Mode::Vnc, &mut lists.vnc_targets),
+    ] {
+        if mode.selected(m) {
+            if let Ok(mut parsed) = Target::parse(input, m) {
+                targets.append(&mut parsed);
+            }
+        }
+    }
+    lists
+}
+
+/// Reads the targets from a scan file in one of the simpler formats
+type Importer = fn(&mut dyn BufRead, &Opts) -> Result<InputLists, String>;
+
+/// Load the targets from a scan file with one of the simpler importers,
+/// adding them to the lists
+fn load_file(
+    file: &str,
+    kind: &str,
+    import: Importer,
+    opts: &Opts,
+    input_lists: &mut InputLists,
+) {
+    info!("Loading {} file {}", kind, file);
+    match File::open(file) {
+        Err(e) => {
+            warn!("Error opening file: {}", e);
+        }
+        Ok(f) => match import(&mut BufReader::new(f), opts) {
+            Err(e) => {
+                warn!("Error parsing {} file: {}", kind, e);
+            }
+            Ok(mut lists) => {
+                debug!("Successfully parsed file");
+                input_lists.append(&mut lists);
+            }
+        },
+    }
+    input_lists.record_source(file);
+}
+
 pub fn generate_target_lists(opts: &Opts) -> InputLists {
     use Mode::*;
     let mut input_lists: InputLists = Default::default();
 
     // Process the optional command-line target argument
-    for t in &opts.targets {
-        let mut parse_successful = false;
-        match &opts.mode {
-            Auto => {
-                // Try parsing as both web and RDP, saving any that stick
-                if let Ok(mut targets) = Target::parse(t, Rdp) {
-                    input_lists.rdp_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as RDP target", t);
-                }
-                if let Ok(mut targets) = Target::parse(t, Web) {
-                    input_lists.web_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as Web target", t);
-                }
-                if let Ok(mut targets) = Target::parse(t, Vnc) {
-                    input_lists.vnc_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as VNC target", t);
-                }
-            }
-            Web => {
-                if let Ok(mut targets) = Target::parse(t, Web) {
-                    input_lists.web_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as Web target", t);
-                }
-            }
-            Rdp => {
-                if let Ok(mut targets) = Target::parse(t, Rdp) {
-                    input_lists.rdp_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as RDP target", t);
-                }
-            }
-            Vnc => {
-                if let Ok(mut targets) = Target::parse(t, Vnc) {
-                    input_lists.vnc_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as VNC target", t);
+    for input in &opts.targets {
+        for t in expand_input(input, opts) {
+            let mut lists = parse_target(&t, opts.mode);
+            if lists.rdp_targets.is_empty()
+                && lists.web_targets.is_empty()
+                && lists.vnc_targets.is_empty()
+            {
+                match opts.mode {
+                    Auto => warn!("Unable to parse {}", t),
+                    mode => warn!("{} is not a valid {} target", t, mode),
                 }
+                continue;
             }
-        }
-        if !parse_successful {
-            warn!("Unable to parse {}", t);
+            debug!("{} parsed as {}", t, lists.types());
+            input_lists.append(&mut lists);
         }
     }
 
+    input_lists.record_source("command line");
+
     // Process the optional input file
     for file_name in &opts.files {
         let mut parse_successful_count: usize = 0;
         let mut parse_total_count: usize = 0;
         let mut parse_unsuccessful_count: usize = 0;
-        // This is horribly deep nesting, but it has to try opening the
-        // provided file, iterate over a reader, parse each line into a
-        // string type and then behave slightly differently depending on
-        // the selected mode, failing gracefully at each stage if any
-        // errors occur.
-        //TODO make nesting less deep somehow
-        match File::open(file_name) {
-            Ok(file) => {
-                let reader = BufReader::new(file);
-                for line in reader.lines() {
-                    debug!("Reading target {:?}", line);
-                    match line {
-                        Ok(t) => {
-                            // Try to parse the line into a Target
-                            parse_total_count += 1;
-
-                            match &opts.mode {
-                                Auto => {
-                                    // Try parsing as web, RDP, and VNC,
-                                    // saving any that stick
-                                    let mut success = false;
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Rdp)
-                                    {
-                                        input_lists
-                                            .rdp_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        success = true;
-                                        info!("{} loaded as RDP target", t);
-                                    }
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Web)
-                                    {
-                                        input_lists
-                                            .web_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        success = true;
-                                        info!("{} loaded as Web target", t);
-                                    }
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Vnc)
-                                    {
-                                        input_lists
-                                            .vnc_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        success = true;
-                                        info!("{} loaded as VNC target", t);
-                                    }
-                                    if !success {
-                                        warn!("Unable to parse {}", t);
-                                        parse_unsuccessful_count += 1;
-                                    }
-                                }
-                                Web => {
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Web)
-                                    {
-                                        input_lists
-                                            .web_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        info!("{} loaded as Web target", t);
-                                    } else {
-                                        warn!(
-                                            "{} is not a valid Web target",
-                                            t
-                                        );
-                                        parse_unsuccessful_count += 1;
-                                    }
-                                }
-                                Rdp => {
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Rdp)
-                                    {
-                                        input_lists
-                                            .rdp_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        info!("{} loaded as RDP target", t);
-                                    } else {
-                                        warn!(
-                                            "{} is not a valid RDP target",
-                                            t
-                                        );
-                                        parse_unsuccessful_count += 1;
-                                    }
-                                }
-                                Vnc => {
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Vnc)
-                                    {
-                                        input_lists
-                                            .vnc_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        info!("{} loaded as VNC target", t);
-                                    } else {
-                                        warn!(
-                                            "{} is not a valid RDP target",
-                                            t
-                                        );
-                                        parse_unsuccessful_count += 1;
-                                    }
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            warn!("Error reading line {}", e);
-                            parse_unsuccessful_count += 1;
-                        }
-                    }
-                }
-            }
+        let reader = match File::open(file_name) {
+            Ok(file) => BufReader::new(file),
             Err(e) => {
                 warn!("Error opening file: {:?}", e);
+                continue;
+            }
+        };
+        for (line_number, line) in reader.lines().enumerate() {
+            debug!("Reading target {:?}", line);
+            let line = match line.map_err(|e| e.to_string()).and_then(|l| {
+                line::parse(&l).map_err(|e| format!("{}: {:?}", e, l))
+            }) {
+                Ok(Some(line)) => line,
+                // Blank line or comment
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("{}:{}: {}", file_name, line_number + 1, e);
+                    parse_unsuccessful_count += 1;
+                    continue;
+                }
+            };
+            parse_total_count += 1;
+
+            // A type at the start of the line narrows down the mode, as
+            // long as it is one of the types selected with --mode
+            let mode = match line.mode {
+                Some(mode) if !opts.mode.selected(mode) => {
+                    debug!("Skipping {} target {}", mode, line.target);
+                    continue;
+                }
+                Some(mode) => mode,
+                None => opts.mode,
+            };
+
+            for t in expand_input(&line.target, opts) {
+                let mut lists = parse_target(&t, mode);
+                let loaded = lists.rdp_targets.len()
+                    + lists.web_targets.len()
+                    + lists.vnc_targets.len();
+                if loaded == 0 {
+                    match mode {
+                        Auto => warn!("Unable to parse {}", t),
+                        _ => warn!("{} is not a valid {} target", t, mode),
+                    }
+                    parse_unsuccessful_count += 1;
+                    continue;
+                }
+                parse_successful_count += loaded;
+                info!("{} loaded as {}", t, lists.types());
+                lists.record_line_details(&line);
+                input_lists.append(&mut lists);
             }
         }
         info!(
@@ -538,6 +821,7 @@ pub fn generate_target_lists(opts: &Opts) -> InputLists {
             file_name,
             parse_unsuccessful_count,
         );
+        input_lists.record_source(file_name);
     }
 
     // Parse nmap file
@@ -548,27 +832,77 @@ pub fn generate_target_lists(opts: &Opts) -> InputLists {
             Err(e) => {
                 warn!("Error opening file: {}", e);
             }
-            Ok(content) => {
-                match NmapResults::parse(&content) {
-                    Err(e) => {
-                        warn!("Error parsing nmap file: {}", e);
-                    }
-                    Ok(results) => {
-                        debug!("Successfully parsed file");
-                        //TODO filter for host being UP
-                        for (host, port) in results.iter_ports() {
-                            // for each host check for some common open ports
-                            // and add relevant ones to the list
-
-                            // this has been broken out into a separate function
-                            // for readability
-                            input_lists
-                                .append(&mut lists_from_nmap(host, port, opts));
-                        }
+            Ok(content) => match NmapResults::parse(&content) {
+                Err(e) => {
+                    warn!("Error parsing nmap file: {}", e);
+                }
+                Ok(results) => {
+                    debug!("Successfully parsed file");
+                    let (mut lists, skipped) =
+                        lists_from_nmap_results(&results, opts);
+                    input_lists.append(&mut lists);
+                    if !skipped.is_empty() {
+                        info!("Skipped from {}: {}", file, skipped);
                     }
                 }
-            }
+            },
         }
+        input_lists.record_source(file);
+    }
+
+    // Parse Metasploit services CSV file
+    for file in &opts.msf_csvs {
+        load_file(
+            file,
+            "Metasploit services",
+            msf::lists_from_msf_csv,
+            opts,
+            &mut input_lists,
+        );
+    }
+
+    // Parse masscan JSON or list file
+    for file in &opts.masscans {
+        load_file(
+            file,
+            "masscan",
+            masscan::lists_from_masscan,
+            opts,
+            &mut input_lists,
+        );
+    }
+
+    // Parse Nmap greppable file
+    for file in &opts.nmap_greps {
+        load_file(
+            file,
+            "Nmap greppable",
+            greppable::lists_from_nmap_grep,
+            opts,
+            &mut input_lists,
+        );
+    }
+
+    // Parse RustScan greppable file
+    for file in &opts.rustscans {
+        load_file(
+            file,
+            "RustScan",
+            greppable::lists_from_rustscan,
+            opts,
+            &mut input_lists,
+        );
+    }
+
+    // Parse web crawler, fuzzer or Burp site map file
+    for file in &opts.url_files {
+        load_file(
+            file,
+            "URL",
+            urls::lists_from_url_file,
+            opts,
+            &mut input_lists,
+        );
     }
 
     // Parse nessus file
@@ -594,33 +928,346 @@ pub fn generate_target_lists(opts: &Opts) -> InputLists {
                             // this has been broken out into a separate function
                             // for readability
                             input_lists.append(&mut lists_from_nessus(
-                                host, port, &opts.mode,
+                                host,
+                                port,
+                                &opts.mode,
+                                opts.dns_hostnames,
                             ));
                         }
                     }
                 }
             }
         }
+        input_lists.record_source(file);
     }
 
     // Put in web paths
     let mut additional_web_targets =
         Vec::with_capacity(input_lists.web_targets.len() * opts.web_path.len());
+    let mut web_path_sources = Vec::new();
     for target in &input_lists.web_targets {
         for path in &opts.web_path {
             if let Target::Url(ref u) = target {
                 let mut u = u.clone();
                 u.set_path(path);
+                // Extra paths come from the same input as the base URL
+                if let Some(source) =
+                    input_lists.sources.get(&target.to_string())
+                {
+                    web_path_sources.push((u.to_string(), source.clone()));
+                }
                 additional_web_targets.push(Target::Url(u));
             }
         }
     }
     input_lists.web_targets.append(&mut additional_web_targets);
+    for (target, source) in web_path_sources {
+        input_lists.sources.entry(target).or_insert(source);
+    }
 
     input_lists.dedup();
+    input_lists.apply_scope(opts);
     input_lists
 }
 
+/// Reasons for not considering a port from an Nmap file as a target
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum NmapSkip {
+    NotTcp,
+    NotOpen,
+    OpenFiltered,
+    LowConfidence,
+}
+
+impl Display for NmapSkip {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use NmapSkip::*;
+        let reason = match self {
+            NotTcp => "not TCP",
+            NotOpen => "not open",
+            OpenFiltered => "open|filtered",
+            LowConfidence => "below the service detection confidence",
+        };
+        write!(fmt, "{}", reason)
+    }
+}
+
+/// Counts of what was skipped when loading an Nmap file, and why
+#[derive(Debug, Default, PartialEq)]
+struct NmapSkipped {
+    hosts_not_up: usize,
+    ports: BTreeMap<NmapSkip, usize>,
+}
+
+impl NmapSkipped {
+    fn is_empty(&self) -> bool {
+        self.hosts_not_up == 0 && self.ports.is_empty()
+    }
+}
+
+impl Display for NmapSkipped {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if self.hosts_not_up > 0 {
+            parts.push(format!("{} hosts not up", self.hosts_not_up));
+        }
+        for (reason, count) in &self.ports {
+            parts.push(format!("{} ports {}", count, reason));
+        }
+        write!(fmt, "{}", parts.join(", "))
+    }
+}
+
+/// Decide whether a port from an Nmap file should be skipped before
+/// looking at its service. Ports must be open TCP ports, with
+/// open|filtered ones only allowed on request. If a minimum service
+/// detection confidence is set then ports without service detection
+/// results are skipped.
+fn nmap_skip_reason(
+    port: &nmap_xml_parser::port::Port,
+    opts: &Opts,
+) -> Option<NmapSkip> {
+    if port.protocol != PortProtocol::Tcp {
+        return Some(NmapSkip::NotTcp);
+    }
+    match port.status.state {
+        PortState::Open => {}
+        PortState::OpenFiltered if opts.nmap_open_filtered => {}
+        PortState::OpenFiltered => return Some(NmapSkip::OpenFiltered),
+        _ => return Some(NmapSkip::NotOpen),
+    }
+    if let Some(min) = opts.nmap_min_confidence {
+        let confidence = port
+            .service_info
+            .as_ref()
+            .map_or(0, |info| info.confidence_level);
+        if confidence < min {
+            return Some(NmapSkip::LowConfidence);
+        }
+    }
+    None
+}
+
+/// Well-known web ports, which are treated as web even without
+/// service detection
+const WEB_PORTS: &[u16] = &[80, 443, 631, 7443, 8080, 8443, 8000, 3000];
+
+/// The details of a scanned service that decide how it is captured,
+/// shared by the importers for scan formats that use Nmap's service
+/// names
+struct Service<'a> {
+    port: u16,
+    /// Service name, e.g. "http" or "ms-wbt-server"
+    name: &'a str,
+    /// Product and version information, e.g. "nginx 1.18.0"
+    product: &'a str,
+    /// Whether the service is known to be wrapped in SSL/TLS
+    tunnel_ssl: bool,
+    /// Whether the service was identified by probing it rather than
+    /// guessed from the port number
+    probed: bool,
+}
+
+impl Service<'_> {
+    /// A service with nothing but its port number to go on
+    fn from_port(port: u16) -> Self {
+        Service {
+            port,
+            name: "",
+            product: "",
+            tunnel_ssl: false,
+            probed: false,
+        }
+    }
+
+    fn is_rdp(&self) -> bool {
+        self.port == 3389 || self.name == "ms-wbt-server"
+    }
+
+    fn is_vnc(&self) -> bool {
+        (5900..=5903).contains(&self.port)
+            || ["vnc", "vnc-1", "vnc-2", "vnc-3"].contains(&self.name)
+    }
+
+    /// Work out which URL schemes to use for a web service, or None if
+    /// the port does not look like a web service. Any service whose
+    /// name or product mentions HTTP is web, as are well-known web
+    /// ports and, if `any_port` is set, every port. Services wrapped in
+    /// SSL/TLS only need https, and services found by probing without
+    /// it are plain http. Otherwise, e.g. for guesses from Nmap's port
+    /// table, both are tried.
+    fn web_schemes(&self, any_port: bool) -> Option<&'static [&'static str]> {
+        let product = self.product.to_lowercase();
+        let is_web = self.name.contains("http")
+            || product.contains("http")
+            || product.contains("nginx");
+        if !is_web && !WEB_PORTS.contains(&self.port) && !any_port {
+            return None;
+        }
+
+        let tls = self.tunnel_ssl
+            || self.name.starts_with("https")
+            || self.name.starts_with("ssl");
+        if tls {
+            Some(&["https"])
+        } else if self.probed && is_web {
+            Some(&["http"])
+        } else {
+            Some(&["https", "http"])
+        }
+    }
+}
+
+/// Details of a port from an Nmap file. Nmap marks services wrapped in
+/// SSL/TLS with tunnel="ssl".
+fn nmap_service(port: &nmap_xml_parser::port::Port) -> Service<'_> {
+    use nmap_xml_parser::port::ServiceMethod;
+
+    let info = port.service_info.as_ref();
+    Service {
+        port: port.port_number,
+        name: info.map_or("", |i| i.name.as_str()),
+        product: info.and_then(|i| i.product.as_deref()).unwrap_or(""),
+        tunnel_ssl: info.and_then(|i| i.tunnel.as_deref()) == Some("ssl"),
+        probed: info.map(|i| &i.method) == Some(&ServiceMethod::Probed),
+    }
+}
+
+/// Build a URL target for a web service, or None if it does not parse
+fn web_target(scheme: &str, host_name: &str, port: u16) -> Option<Target> {
+    let u = format!("{}://{}:{}/", scheme, host_name, port);
+    match Url::parse(&u) {
+        Ok(u) => Some(Target::Url(u)),
+        Err(e) => {
+            warn!("Error parsing {} as URL: {}", u, e);
+            None
+        }
+    }
+}
+
+/// Add the RDP, VNC and web targets for a single service on an address,
+/// for importers that do not know the host's names
+fn lists_from_service(
+    addr: IpAddr,
+    service: &Service,
+    opts: &Opts,
+    list: &mut InputLists,
+) {
+    let sockaddr = SocketAddr::new(addr, service.port);
+    let schemes = service
+        .web_schemes(false)
+        .filter(|_| opts.mode.selected(Mode::Web));
+    if opts.mode.selected(Mode::Rdp) && service.is_rdp() {
+        debug!("Identified RDP");
+        list.rdp_targets.push(Target::Address(sockaddr));
+    } else if let Some(schemes) = schemes {
+        debug!("Identified web");
+        let host_name = match addr {
+            IpAddr::V6(a) => format!("[{}]", a),
+            IpAddr::V4(a) => a.to_string(),
+        };
+        for scheme in schemes {
+            list.web_targets.extend(web_target(
+                scheme,
+                &host_name,
+                service.port,
+            ));
+        }
+    } else if opts.mode.selected(Mode::Vnc) && service.is_vnc() {
+        debug!("Identified VNC");
+        list.vnc_targets.push(Target::Address(sockaddr));
+    }
+}
+
+/// Add the URLs for a web service from an Nmap file to the list, one
+/// for each of the host's IP addresses and one for each of its
+/// hostnames. Name-based virtual hosts only serve the real application
+/// when accessed by name, so the hostnames are recorded against the
+/// scanned address for the browser to connect to. Only the names given
+/// to Nmap are used unless `dns_hostnames` is set, as reverse DNS names
+/// such as those of cloud hosts rarely select a different site.
+fn nmap_web_targets(
+    host: &nmap_xml_parser::host::Host,
+    port: &nmap_xml_parser::port::Port,
+    any_port: bool,
+    dns_hostnames: bool,
+    list: &mut InputLists,
+) {
+    use nmap_xml_parser::host::{Address, HostnameType};
+
+    let schemes = nmap_service(port).web_schemes(any_port).unwrap_or_default();
+    let mut host_names = Vec::new();
+    let mut first_addr = None;
+    for address in host.addresses() {
+        match address {
+            Address::IpAddr(a) => {
+                first_addr.get_or_insert(*a);
+                host_names.push(match a {
+                    IpAddr::V6(a) => format!("[{}]", a),
+                    IpAddr::V4(a) => a.to_string(),
+                });
+            }
+            Address::MacAddr(a) => {
+                trace!("Ignoring MAC address {}", a);
+            }
+        }
+    }
+    if let Some(addr) = first_addr {
+        for hostname in host.host_names() {
+            let wanted =
+                dns_hostnames || matches!(hostname.source, HostnameType::User);
+            if wanted && hostname.name.parse::<IpAddr>().is_err() {
+                list.record_hostname(&hostname.name, addr);
+                host_names.push(hostname.name.clone());
+            }
+        }
+    }
+
+    for host_name in host_names {
+        for scheme in schemes {
+            list.web_targets.extend(web_target(
+                scheme,
+                &host_name,
+                port.port_number,
+            ));
+        }
+    }
+}
+
+/// Collect the targets from the hosts that are up in an Nmap file
+fn lists_from_nmap_results(
+    results: &NmapResults,
+    opts: &Opts,
+) -> (InputLists, NmapSkipped) {
+    let mut lists: InputLists = Default::default();
+    let mut skipped: NmapSkipped = Default::default();
+    for host in results.hosts() {
+        if host.status.state != HostState::Up {
+            debug!("Skipping host that is {:?}", host.status.state);
+            skipped.hosts_not_up += 1;
+            continue;
+        }
+        for port in host.port_info.ports() {
+            if let Some(reason) = nmap_skip_reason(port, opts) {
+                trace!("Skipping port {}: {}", port.port_number, reason);
+                *skipped.ports.entry(reason).or_default() += 1;
+                continue;
+            }
+            // for each host check for some common open ports
+            // and add relevant ones to the list
+
+            // this has been broken out into a separate function
+            // for readability
+            lists.append(&mut lists_from_nmap(host, port, opts));
+        }
+    }
+    (lists, skipped)
+}
+
+/// Add the targets for a port on a host from an Nmap file. Web services
+/// get a URL for each of the host's addresses and hostnames, while
+/// anything else is added for each address in the same way as the
+/// other scan importers.
 fn lists_from_nmap(
     host: &nmap_xml_parser::host::Host,
     port: &nmap_xml_parser::port::Port,
@@ -630,171 +1277,95 @@ fn lists_from_nmap(
 
     let mut list: InputLists = Default::default();
 
-    //TODO service discovery for ports identified as
-    // "web", etc.
-    //TODO break this out into a function
-    //TODO code reuse
     debug!("Parsing host {:?}", (host, port));
-    if port.status.state == PortState::Open {
-        debug!("open port");
-        // Found an open port, now add it to the
-        // input lists if it is appropriate
-        //TODO identify Web
-        let service_name = if let Some(info) = &port.service_info {
-            info.name.as_str()
-        } else {
-            ""
-        };
-        match (port.port_number, service_name) {
-            // RDP signatures
-            (3389, _) | (_, "ms-wbt-server")
-                if opts.mode.selected(Mode::Rdp) =>
-            {
-                debug!("Identified RDP");
-                let port = port.port_number;
-                // Iterate over the host's addresses. It may have multiple
-                // IPv6, IPv4, and MAC addresses and we want to add them
-                // all (well, maybe not the MAC addresses)
-                for address in host.addresses() {
-                    let target_string = match address {
-                        Address::IpAddr(IpAddr::V6(a)) => {
-                            trace!("address: {:?}", a);
-                            format!("[{}]:{}", a, port)
-                        }
-                        Address::IpAddr(IpAddr::V4(a)) => {
-                            trace!("legacy address: {:?}", a);
-                            format!("{}:{}", a, port)
-                        }
-                        Address::MacAddr(a) => {
-                            trace!("Ignoring MAC address {}", a);
-                            // Ignore the MAC address and move on
-                            continue;
-                        }
-                    };
-
-                    // target_string now contains a string sockaddr
-                    // representation, so we parse it as RDP and see what
-                    // happens
-                    match Target::parse(&target_string, Mode::Rdp) {
-                        Ok(mut target) => {
-                            debug!("Successfully parsed as RDP");
-                            list.rdp_targets.append(&mut target);
-                        }
-                        Err(e) => {
-                            warn!("Error parsing target as RDP: {}", e);
-                        }
-                    }
+    // The port has passed nmap_skip_reason, so add it to the input
+    // lists if it is appropriate
+    let service = nmap_service(port);
+    let rdp = opts.mode.selected(Mode::Rdp) && service.is_rdp();
+    let web = opts.mode.selected(Mode::Web);
+    if !rdp && web && service.web_schemes(false).is_some() {
+        debug!("Identified web");
+        nmap_web_targets(host, port, false, opts.dns_hostnames, &mut list);
+    } else if rdp || (opts.mode.selected(Mode::Vnc) && service.is_vnc()) {
+        // The host may have several IPv4 and IPv6 addresses, as well as
+        // a MAC address
+        for address in host.addresses() {
+            match address {
+                Address::IpAddr(a) => {
+                    lists_from_service(*a, &service, opts, &mut list);
                 }
-            }
-            // HTTP(S) signatures
-            (80, _)
-            | (443, _)
-            | (631, _)
-            | (7443, _)
-            | (8080, _)
-            | (8443, _)
-            | (8000, _)
-            | (3000, _)
-            | (_, "http")
-            | (_, "http-mgt")
-            | (_, "https")
-            | (_, "http-alt")
-            | (_, "https-alt")
-                if opts.mode.selected(Mode::Web) =>
-            {
-                debug!("Idenfified web");
-                let port = port.port_number;
-                // Iterate over the host's addresses. It may have multiple
-                // IPv6, IPv4, and MAC addresses and we want to add them
-                // all (well, maybe not the MAC addresses)
-                for address in host.addresses() {
-                    let target_string = match address {
-                        Address::IpAddr(IpAddr::V6(a)) => {
-                            trace!("address: {:?}", a);
-                            format!("[{}]:{}", a, port)
-                        }
-                        Address::IpAddr(IpAddr::V4(a)) => {
-                            trace!("legacy address: {:?}", a);
-                            format!("{}:{}", a, port)
-                        }
-                        Address::MacAddr(a) => {
-                            trace!("Ignoring MAC address {}", a);
-                            // Ignore the MAC address and move on
-                            continue;
-                        }
-                    };
-
-                    // target_string now contains a string sockaddr
-                    // representation, so we parse it as Web and see what
-                    // happens
-                    match Target::parse(&target_string, Mode::Web) {
-                        Ok(mut target) => {
-                            debug!("Successfully parsed as Web");
-                            list.web_targets.append(&mut target);
-                        }
-                        Err(e) => {
-                            warn!("Error parsing target as Web: {}", e);
-                        }
-                    }
+                Address::MacAddr(a) => {
+                    trace!("Ignoring MAC address {}", a);
                 }
             }
-            // VNC signatures
-            (5900, _)
-            | (5901, _)
-            | (5902, _)
-            | (5903, _)
-            | (_, "vnc")
-            | (_, "vnc-1")
-            | (_, "vnc-2")
-            | (_, "vnc-3")
-                if opts.mode.selected(Mode::Vnc) =>
-            {
-                debug!("Identified VNC");
-                let port = port.port_number;
-                // Iterate over the host's addresses. It may have multiple
-                // IPv6, IPv4, and MAC addresses and we want to add them
-                // all (well, maybe not the MAC addresses)
-                for address in host.addresses() {
-                    let target_string = match address {
-                        Address::IpAddr(IpAddr::V6(a)) => {
-                            trace!("address: {:?}", a);
-                            format!("[{}]:{}", a, port)
-                        }
-                        Address::IpAddr(IpAddr::V4(a)) => {
-                            trace!("legacy address: {:?}", a);
-                            format!("{}:{}", a, port)
-                        }
-                        Address::MacAddr(a) => {
-                            trace!("Ignoring MAC address {}", a);
-                            // Ignore the MAC address and move on
-                            continue;
-                        }
-                    };
+        }
+    } else if opts.nmap_all_web && web {
+        // Anything else is only tried as web on request
+        debug!("Trying unidentified port as web");
+        nmap_web_targets(host, port, true, opts.dns_hostnames, &mut list);
+    }
+    list
+}
 
-                    // target_string now contains a string sockaddr
-                    // representation, so we parse it as RDP and see what
-                    // happens
-                    match Target::parse(&target_string, Mode::Vnc) {
-                        Ok(mut target) => {
-                            debug!("Successfully parsed as VNC");
-                            list.vnc_targets.append(&mut target);
-                        }
-                        Err(e) => {
-                            warn!("Error parsing target as VNC: {}", e);
-                        }
-                    }
-                }
+/// Nessus plugins that only report on services wrapped in SSL/TLS, so
+/// their presence on a port means that it should be accessed over HTTPS
+const NESSUS_TLS_PLUGINS: &[u32] = &[
+    10863, // SSL Certificate Information
+    21643, // SSL Cipher Suites Supported
+    56984, // SSL / TLS Versions Supported
+    51192, // SSL Certificate Cannot Be Trusted
+    57582, // SSL Self-Signed Certificate
+];
+
+/// Nessus "Service Detection" plugin. Its output says whether the
+/// service was found "through SSL" or "through TLSv1.2", etc.
+const NESSUS_SERVICE_DETECTION: u32 = 22964;
+
+/// Work out which URL schemes to use for a web service in a nessus
+/// file. Evidence of SSL/TLS from plugins on the same port takes
+/// priority, followed by the service detection output and the service
+/// name. If none of these settle it then both schemes are returned.
+fn nessus_web_schemes(
+    host: &nessus_xml_parser::ReportHost,
+    port: &nessus_xml_parser::Port,
+) -> &'static [&'static str] {
+    let mut tls = false;
+    let mut plaintext = false;
+    for item in host
+        .items
+        .iter()
+        .filter(|i| i.port.id == port.id && i.port.protocol == port.protocol)
+    {
+        if NESSUS_TLS_PLUGINS.contains(&item.plugin_id) {
+            tls = true;
+        } else if item.plugin_id == NESSUS_SERVICE_DETECTION {
+            match &item.plugin_output {
+                Some(o) if o.contains("through SSL") => tls = true,
+                Some(o) if o.contains("through TLS") => tls = true,
+                Some(_) => plaintext = true,
+                None => {}
             }
-            _ => {}
         }
     }
-    list
+    trace!(
+        "Port {} tls evidence: {}, plaintext evidence: {}",
+        port.id,
+        tls,
+        plaintext
+    );
+
+    match (tls, plaintext, port.service.as_str()) {
+        (true, _, _) | (false, false, "https") => &["https"],
+        (false, true, _) | (false, false, "http") => &["http"],
+        _ => &["https", "http"],
+    }
 }
 
 fn lists_from_nessus(
     host: &nessus_xml_parser::ReportHost,
     port: nessus_xml_parser::Port,
     mode: &Mode,
+    dns_hostnames: bool,
 ) -> InputLists {
     let mut list: InputLists = Default::default();
 
@@ -821,7 +1392,55 @@ fn lists_from_nessus(
                 if mode.selected(Mode::Web) =>
             {
                 debug!("Identified Web");
-                list.web_targets.push(Target::Address(target));
+                // Capture the scanned address as well as the name it
+                // was scanned by, and the names Nessus found in DNS if
+                // asked, so that name-based virtual hosts are captured
+                // correctly
+                let props = &host.properties;
+                let dns_names = [&props.host_fqdn, &props.host_rdns]
+                    .into_iter()
+                    .flatten()
+                    .filter(|_| dns_hostnames)
+                    .cloned();
+                let addr = props
+                    .host_ip
+                    .as_deref()
+                    .unwrap_or(&host.name)
+                    .parse::<IpAddr>()
+                    .ok();
+                let mut names: Vec<String> = Vec::new();
+                for name in addr
+                    .map(|a| a.to_string())
+                    .into_iter()
+                    .chain(Some(host.name.clone()))
+                    .chain(dns_names)
+                {
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+                for name in names {
+                    let host_name = match name.parse::<IpAddr>() {
+                        Ok(IpAddr::V6(a)) => format!("[{}]", a),
+                        Ok(IpAddr::V4(a)) => a.to_string(),
+                        Err(_) => {
+                            if let Some(addr) = addr {
+                                list.record_hostname(&name, addr);
+                            }
+                            name
+                        }
+                    };
+                    for scheme in nessus_web_schemes(host, &port) {
+                        let u =
+                            format!("{}://{}:{}/", scheme, host_name, port.id);
+                        match Url::parse(&u) {
+                            Ok(u) => list.web_targets.push(Target::Url(u)),
+                            Err(e) => {
+                                warn!("Error parsing {} as URL: {}", u, e)
+                            }
+                        }
+                    }
+                }
             }
             (5900, _) | (5901, _) | (5902, _) | (5903, _) | (_, "vnc")
                 if mode.selected(Mode::Vnc) =>
@@ -839,6 +1458,13 @@ fn lists_from_nessus(
 #[cfg(test)]
 mod test {
     use super::*;
+
+    /// Set the source of every target in the lists
+    fn sourced(mut lists: InputLists, source: &str) -> InputLists {
+        lists.record_source(source);
+        lists
+    }
+
     #[test]
     fn parse_target_as_url() {
         use Mode::{Rdp, Vnc, Web};
@@ -1087,6 +1713,7 @@ mod test {
                     )],
                     web_targets: Vec::new(),
                     vnc_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Rdp,
             ),
@@ -1102,6 +1729,7 @@ mod test {
                     )],
                     web_targets: Vec::new(),
                     vnc_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Auto,
             ),
@@ -1113,6 +1741,7 @@ mod test {
                         Url::parse("https://[2001:db8::6]:8080").unwrap(),
                     )],
                     vnc_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Web,
             ),
@@ -1124,6 +1753,7 @@ mod test {
                         Url::parse("https://[2001:db8::6]").unwrap(),
                     )],
                     vnc_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Auto,
             ),
@@ -1140,6 +1770,7 @@ mod test {
                         ),
                     ],
                     vnc_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Web,
             ),
@@ -1155,6 +1786,7 @@ mod test {
                     )],
                     web_targets: Vec::new(),
                     vnc_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Rdp,
             ),
@@ -1183,6 +1815,7 @@ mod test {
                             .next()
                             .unwrap(),
                     )],
+                    ..Default::default()
                 },
                 Auto,
             ),
@@ -1195,10 +1828,52 @@ mod test {
 
             let parsed = generate_target_lists(&opts);
 
-            assert_eq!(parsed, input_lists);
+            assert_eq!(parsed, sourced(input_lists, "command line"));
         }
     }
 
+    #[test]
+    fn target_lists_from_range() {
+        use Mode::{Rdp, Vnc, Web};
+        let mut opts = Opts {
+            range_limit: 4,
+            ..Default::default()
+        };
+
+        let sockaddr = |s: &str| {
+            Target::Address(s.to_socket_addrs().unwrap().next().unwrap())
+        };
+
+        opts.targets = vec!["192.0.2.1-2".into()];
+        opts.mode = Rdp;
+        let parsed = generate_target_lists(&opts);
+        assert_eq!(
+            parsed.rdp_targets,
+            vec![sockaddr("192.0.2.1:3389"), sockaddr("192.0.2.2:3389")]
+        );
+
+        opts.targets = vec!["2001:db8::/127".into()];
+        opts.mode = Vnc;
+        let parsed = generate_target_lists(&opts);
+        assert_eq!(
+            parsed.vnc_targets,
+            vec![
+                sockaddr("[2001:db8::1]:5900"),
+                sockaddr("[2001:db8::]:5900")
+            ]
+        );
+
+        opts.targets = vec!["192.0.2.4/31".into()];
+        opts.mode = Web;
+        let parsed = generate_target_lists(&opts);
+        assert_eq!(parsed.web_targets.len(), 4);
+
+        // Ranges over the limit are refused entirely
+        opts.targets = vec!["192.0.2.0/29".into()];
+        let parsed = generate_target_lists(&opts);
+        assert_eq!(parsed, Default::default());
+    }
+
     #[test]
     fn load_from_nmap_xml() {
         // Load xml from a file and parse it
@@ -1234,6 +1909,7 @@ mod test {
                     ),
                 ],
                 vnc_targets: Vec::new(),
+                ..Default::default()
             },
         )];
         let mut opts: Opts = Default::default();
@@ -1243,7 +1919,589 @@ mod test {
             let parsed = generate_target_lists(&opts);
             eprintln!("Parsed: {:?}", parsed);
 
-            assert_eq!(parsed, case.1);
+            assert_eq!(parsed, sourced(case.1, case.0));
+        }
+    }
+
+    #[test]
+    fn filter_nmap_xml() {
+        use Mode::{Rdp, Vnc, Web};
+        let content = fs::read_to_string("test/filtering.xml").unwrap();
+        let results = NmapResults::parse(&content).unwrap();
+        let addr = |s: &str| {
+            Target::Address(s.to_socket_addrs().unwrap().next().unwrap())
+        };
+        let url = |s: &str| Target::Url(Url::parse(s).unwrap());
+
+        let test_cases = vec![
+            (
+                Opts::default(),
+                vec![
+                    (Rdp, addr("192.0.2.11:3389")),
+                    (Web, url("https://192.0.2.11:80/")),
+                    (Web, url("http://192.0.2.11/")),
+                    (Web, url("http://192.0.2.11:8080/")),
+                ],
+                "1 hosts not up, 1 ports not TCP, 1 ports open|filtered",
+            ),
+            (
+                Opts {
+                    nmap_open_filtered: true,
+                    ..Default::default()
+                },
+                vec![
+                    (Rdp, addr("192.0.2.11:3389")),
+                    (Web, url("https://192.0.2.11:80/")),
+                    (Web, url("http://192.0.2.11/")),
+                    (Web, url("http://192.0.2.11:8080/")),
+                    (Vnc, addr("192.0.2.11:5900")),
+                ],
+                "1 hosts not up, 1 ports not TCP",
+            ),
+            (
+                Opts {
+                    nmap_min_confidence: Some(8),
+                    ..Default::default()
+                },
+                vec![
+                    (Rdp, addr("192.0.2.11:3389")),
+                    (Web, url("http://192.0.2.11:8080/")),
+                ],
+                concat!(
+                    "1 hosts not up, 1 ports not TCP, 1 ports open|filtered,",
+                    " 1 ports below the service detection confidence"
+                ),
+            ),
+        ];
+
+        for (opts, expected, summary) in test_cases {
+            eprintln!("Test case: {:?}", summary);
+            let (lists, skipped) = lists_from_nmap_results(&results, &opts);
+            let targets: Vec<(Mode, Target)> = lists
+                .rdp_targets
+                .into_iter()
+                .map(|t| (Rdp, t))
+                .chain(lists.web_targets.into_iter().map(|t| (Web, t)))
+                .chain(lists.vnc_targets.into_iter().map(|t| (Vnc, t)))
+                .collect();
+            assert_eq!(targets, expected);
+            assert_eq!(skipped.to_string(), summary);
+        }
+    }
+
+    #[test]
+    fn nmap_service_detection() {
+        let content = fs::read_to_string("test/services.xml").unwrap();
+        let results = NmapResults::parse(&content).unwrap();
+        let url = |s: &str| Target::Url(Url::parse(s).unwrap());
+
+        let test_cases = vec![
+            (
+                false,
+                vec![
+                    url("https://192.0.2.20/"),
+                    url("http://192.0.2.20:8888/"),
+                    url("https://192.0.2.20:9443/"),
+                    url("https://192.0.2.20:10000/"),
+                ],
+            ),
+            (
+                true,
+                vec![
+                    url("https://192.0.2.20:22/"),
+                    url("http://192.0.2.20:22/"),
+                    url("https://192.0.2.20/"),
+                    url("https://192.0.2.20:4443/"),
+                    url("http://192.0.2.20:4443/"),
+                    url("http://192.0.2.20:8888/"),
+                    url("https://192.0.2.20:9443/"),
+                    url("https://192.0.2.20:10000/"),
+                ],
+            ),
+        ];
+
+        for (nmap_all_web, expected) in test_cases {
+            eprintln!("Test case: {:?}", nmap_all_web);
+            let opts = Opts {
+                nmap_all_web,
+                ..Default::default()
+            };
+            let (lists, _) = lists_from_nmap_results(&results, &opts);
+            assert_eq!(lists.web_targets, expected);
+            assert_eq!(lists.rdp_targets.len(), 1);
+            assert_eq!(lists.vnc_targets.len(), 1);
+        }
+    }
+
+    #[test]
+    fn nmap_hostnames() {
+        let content = fs::read_to_string("test/davi.dyoung.tech.xml").unwrap();
+        let results = NmapResults::parse(&content).unwrap();
+        let url = |s: &str| Target::Url(Url::parse(s).unwrap());
+        let addr: IpAddr = "151.236.222.35".parse().unwrap();
+
+        // Only the name given to Nmap by default, not the PTR name
+        let (lists, _) = lists_from_nmap_results(&results, &Opts::default());
+        assert_eq!(
+            lists.web_targets,
+            vec![
+                url("https://151.236.222.35/"),
+                url("https://davi.dyoung.tech/"),
+            ]
+        );
+
+        let opts = Opts {
+            dns_hostnames: true,
+            ..Default::default()
+        };
+        let (lists, _) = lists_from_nmap_results(&results, &opts);
+        assert_eq!(
+            lists.web_targets,
+            vec![
+                url("https://151.236.222.35/"),
+                url("https://davi.dyoung.tech/"),
+                url("https://social.dyoung.tech/"),
+            ]
+        );
+        assert_eq!(
+            lists.hostnames,
+            [
+                ("davi.dyoung.tech".to_string(), addr),
+                ("social.dyoung.tech".to_string(), addr),
+            ]
+            .into_iter()
+            .collect()
+        );
+
+        let related = lists.related_targets();
+        let test_cases = vec![
+            (
+                "https://151.236.222.35/",
+                vec![
+                    "https://davi.dyoung.tech/",
+                    "https://social.dyoung.tech/",
+                ],
+            ),
+            (
+                "https://davi.dyoung.tech/",
+                vec!["https://151.236.222.35/", "https://social.dyoung.tech/"],
+            ),
+            (
+                "https://social.dyoung.tech/",
+                vec!["https://151.236.222.35/", "https://davi.dyoung.tech/"],
+            ),
+        ];
+        for (target, expected) in test_cases {
+            eprintln!("Test case: {:?}", target);
+            assert_eq!(related[target], expected);
+        }
+    }
+
+    #[test]
+    fn hostname_found_at_two_addresses() {
+        let mut lists: InputLists = Default::default();
+        lists.record_hostname("www.example.com", "192.0.2.1".parse().unwrap());
+        let mut other: InputLists = Default::default();
+        other.record_hostname("www.example.com", "192.0.2.2".parse().unwrap());
+        lists.append(&mut other);
+        assert_eq!(
+            lists.hostnames["www.example.com"],
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn load_from_msf_csv() {
+        let expected = InputLists {
+            rdp_targets: vec![Target::Address(
+                "192.0.2.30:3389".to_socket_addrs().unwrap().next().unwrap(),
+            )],
+            web_targets: vec![
+                // Probed, so plain HTTP
+                Target::Url(Url::parse("http://192.0.2.30/").unwrap()),
+                Target::Url(Url::parse("http://[2001:db8::30]:8000/").unwrap()),
+                Target::Url(Url::parse("https://192.0.2.30/").unwrap()),
+            ],
+            vnc_targets: vec![Target::Address(
+                "192.0.2.30:5901".to_socket_addrs().unwrap().next().unwrap(),
+            )],
+            ..Default::default()
+        };
+        let opts = Opts {
+            msf_csvs: vec!["test/msf_services.csv".into()],
+            ..Default::default()
+        };
+        let parsed = generate_target_lists(&opts);
+        eprintln!("Parsed: {:?}", parsed);
+
+        assert_eq!(parsed, sourced(expected, "test/msf_services.csv"));
+    }
+
+    #[test]
+    fn load_from_port_sweeps() {
+        let addr = |s: &str| {
+            Target::Address(s.to_socket_addrs().unwrap().next().unwrap())
+        };
+        let url = |s: &str| Target::Url(Url::parse(s).unwrap());
+        let masscan = InputLists {
+            rdp_targets: vec![addr("192.0.2.50:3389")],
+            web_targets: vec![
+                url("http://192.0.2.50:8443/"),
+                url("https://192.0.2.50:8443/"),
+            ],
+            vnc_targets: vec![addr("192.0.2.51:5900")],
+            ..Default::default()
+        };
+        let nmap_grep = InputLists {
+            rdp_targets: vec![addr("192.0.2.60:3389")],
+            web_targets: vec![
+                url("http://192.0.2.60/"),
+                url("https://192.0.2.60/"),
+            ],
+            ..Default::default()
+        };
+
+        let test_cases = vec![
+            (
+                Opts {
+                    masscans: vec!["test/masscan.json".into()],
+                    ..Default::default()
+                },
+                masscan.clone(),
+            ),
+            (
+                Opts {
+                    masscans: vec!["test/masscan.txt".into()],
+                    ..Default::default()
+                },
+                masscan,
+            ),
+            (
+                Opts {
+                    nmap_greps: vec!["test/nmap.gnmap".into()],
+                    ..Default::default()
+                },
+                nmap_grep.clone(),
+            ),
+            (
+                Opts {
+                    nmap_greps: vec!["test/nmap.gnmap".into()],
+                    nmap_open_filtered: true,
+                    ..Default::default()
+                },
+                InputLists {
+                    vnc_targets: vec![addr("192.0.2.60:5900")],
+                    ..nmap_grep
+                },
+            ),
+            (
+                Opts {
+                    rustscans: vec!["test/rustscan.txt".into()],
+                    ..Default::default()
+                },
+                InputLists {
+                    rdp_targets: vec![addr("192.0.2.70:3389")],
+                    web_targets: vec![
+                        url("http://192.0.2.70/"),
+                        url("http://[2001:db8::70]:8000/"),
+                        url("https://192.0.2.70:80/"),
+                        url("https://[2001:db8::70]:8000/"),
+                    ],
+                    vnc_targets: vec![addr("[2001:db8::70]:5901")],
+                    ..Default::default()
+                },
+            ),
+        ];
+
+        for (opts, expected) in test_cases {
+            let file = opts
+                .masscans
+                .iter()
+                .chain(&opts.nmap_greps)
+                .chain(&opts.rustscans)
+                .next()
+                .unwrap()
+                .clone();
+            eprintln!("Test case: {:?}", file);
+            let parsed = generate_target_lists(&opts);
+            eprintln!("Parsed: {:?}", parsed);
+
+            assert_eq!(parsed, sourced(expected, &file));
+        }
+    }
+
+    #[test]
+    fn load_from_url_file() {
+        let url = |s: &str| Target::Url(Url::parse(s).unwrap());
+        let test_cases = vec![
+            (
+                Vec::new(),
+                vec![
+                    url("https://www.example.com/"),
+                    url("https://www.example.com/admin/login?next=%2F"),
+                    url("https://www.example.com/missing"),
+                ],
+            ),
+            (
+                vec![(200, 299), (401, 401)],
+                vec![
+                    url("https://www.example.com/"),
+                    url("https://www.example.com/admin/login?next=%2F"),
+                ],
+            ),
+        ];
+
+        for (url_status, web_targets) in test_cases {
+            eprintln!("Test case: {:?}", url_status);
+            let opts = Opts {
+                url_files: vec!["test/burp_sitemap.xml".into()],
+                url_status,
+                ..Default::default()
+            };
+            let expected = InputLists {
+                web_targets,
+                ..Default::default()
+            };
+            let parsed = generate_target_lists(&opts);
+            eprintln!("Parsed: {:?}", parsed);
+
+            assert_eq!(parsed, sourced(expected, "test/burp_sitemap.xml"));
+        }
+    }
+
+    #[test]
+    fn load_from_target_file() {
+        let addr = |s: &str| {
+            Target::Address(s.to_socket_addrs().unwrap().next().unwrap())
+        };
+        let url = |s: &str| Target::Url(Url::parse(s).unwrap());
+        let web_targets = vec![
+            url("http://192.0.2.92:8443/"),
+            url("http://192.0.2.93/#/login"),
+            url("https://192.0.2.92:8443/"),
+        ];
+        let web_tags = [
+            (
+                "http://192.0.2.92:8443/".to_string(),
+                vec!["dmz".to_string()],
+            ),
+            (
+                "https://192.0.2.92:8443/".to_string(),
+                vec!["dmz".to_string()],
+            ),
+        ];
+
+        let test_cases = vec![
+            (
+                Mode::Auto,
+                InputLists {
+                    rdp_targets: vec![addr("192.0.2.90:3389")],
+                    web_targets: web_targets.clone(),
+                    vnc_targets: vec![addr("192.0.2.91:5901")],
+                    credentials: [
+                        (
+                            "192.0.2.90:3389".to_string(),
+                            Credentials {
+                                user: Some("admin".into()),
+                                pass: Some("correct horse".into()),
+                                domain: Some("CORP".into()),
+                            },
+                        ),
+                        (
+                            "192.0.2.91:5901".to_string(),
+                            Credentials {
+                                pass: Some("secret".into()),
+                                ..Default::default()
+                            },
+                        ),
+                    ]
+                    .into_iter()
+                    .collect(),
+                    tags: web_tags
+                        .iter()
+                        .cloned()
+                        .chain([(
+                            "192.0.2.90:3389".to_string(),
+                            vec!["dc".to_string()],
+                        )])
+                        .collect(),
+                    ..Default::default()
+                },
+            ),
+            // Lines for other types of target are skipped
+            (
+                Mode::Web,
+                InputLists {
+                    web_targets,
+                    tags: web_tags.iter().cloned().collect(),
+                    ..Default::default()
+                },
+            ),
+        ];
+
+        for (mode, expected) in test_cases {
+            eprintln!("Test case: {:?}", mode);
+            let opts = Opts {
+                files: vec!["test/targets.txt".into()],
+                mode,
+                ..Default::default()
+            };
+            let parsed = generate_target_lists(&opts);
+            eprintln!("Parsed: {:?}", parsed);
+
+            assert_eq!(parsed, sourced(expected, "test/targets.txt"));
+        }
+    }
+
+    #[test]
+    fn scope_and_exclude_lists() {
+        let addr = |s: &str| {
+            Target::Address(s.to_socket_addrs().unwrap().next().unwrap())
+        };
+        let opts = Opts {
+            files: vec!["test/targets.txt".into()],
+            scope: vec!["test/scope.txt".into()],
+            exclude: vec!["test/exclude.txt".into()],
+            ..Default::default()
+        };
+        let parsed = generate_target_lists(&opts);
+        eprintln!("Parsed: {:?}", parsed);
+
+        assert_eq!(parsed.rdp_targets, vec![addr("192.0.2.90:3389")]);
+        assert_eq!(
+            parsed.web_targets,
+            vec![Target::Url(
+                Url::parse("http://192.0.2.93/#/login").unwrap()
+            )]
+        );
+        assert!(parsed.vnc_targets.is_empty());
+        let excluded: Vec<(&str, &str)> = parsed
+            .excluded
+            .iter()
+            .map(|(t, r)| (t.as_str(), r.as_str()))
+            .collect();
+        assert_eq!(
+            excluded,
+            vec![
+                ("192.0.2.91:5901", "excluded by 192.0.2.91"),
+                ("http://192.0.2.92:8443/", "not in scope"),
+                ("https://192.0.2.92:8443/", "not in scope"),
+            ]
+        );
+
+        // A scope file that can no longer be read excludes everything
+        let opts = Opts {
+            scope: vec!["test/missing_scope.txt".into()],
+            ..opts
+        };
+        let parsed = generate_target_lists(&opts);
+        assert!(parsed.rdp_targets.is_empty());
+        assert!(parsed.web_targets.is_empty());
+        assert_eq!(parsed.excluded.len(), 5);
+    }
+
+    #[test]
+    fn exclude_resolved_hostnames() {
+        let mut lists = InputLists {
+            web_targets: vec![
+                Target::Url(Url::parse("http://localhost:8080/").unwrap()),
+                Target::Url(Url::parse("http://scrying.invalid/").unwrap()),
+                Target::Url(Url::parse("http://192.0.2.1/").unwrap()),
+            ],
+            ..Default::default()
+        };
+        let opts = Opts {
+            exclude: vec!["test/exclude_loopback.txt".into()],
+            ..Default::default()
+        };
+        lists.apply_scope(&opts);
+        eprintln!("Excluded: {:?}", lists.excluded);
+
+        assert_eq!(
+            lists.web_targets,
+            vec![Target::Url(Url::parse("http://192.0.2.1/").unwrap())]
+        );
+        assert!(
+            lists.excluded["http://localhost:8080/"].starts_with("excluded by")
+        );
+        assert_eq!(
+            lists.excluded["http://scrying.invalid/"],
+            "scrying.invalid could not be resolved to check the exclude list"
+        );
+    }
+
+    #[test]
+    fn credentials_override_options() {
+        let opts = Opts {
+            rdp_user: Some("user".into()),
+            rdp_pass: Some("global".into()),
+            rdp_domain: Some("DOMAIN".into()),
+            ..Default::default()
+        };
+        let credentials = Credentials {
+            pass: Some("local".into()),
+            ..Default::default()
+        };
+        let applied = credentials.apply(&opts);
+        assert_eq!(applied.rdp_user.as_deref(), Some("user"));
+        assert_eq!(applied.rdp_pass.as_deref(), Some("local"));
+        assert_eq!(applied.rdp_domain.as_deref(), Some("DOMAIN"));
+        assert_eq!(applied.vnc_auth.as_deref(), Some("local"));
+    }
+
+    #[test]
+    fn load_from_nessus() {
+        let url = |s: &str| Target::Url(Url::parse(s).unwrap());
+        let web_targets = vec![
+            // Plain HTTP according to service detection
+            url("http://192.0.2.10/"),
+            // Unclear, so both schemes are tried
+            url("http://192.0.2.10:8080/"),
+            // TLS according to the SSL plugins
+            url("https://192.0.2.10/"),
+            // TLS according to service detection
+            url("https://192.0.2.10:8000/"),
+            url("https://192.0.2.10:8080/"),
+            url("https://[2001:db8::10]/"),
+        ];
+        let expected = InputLists {
+            rdp_targets: vec![Target::Address(
+                "192.0.2.10:3389".to_socket_addrs().unwrap().next().unwrap(),
+            )],
+            web_targets: web_targets.clone(),
+            ..Default::default()
+        };
+        // The host-fqdn is captured as well as the address on request
+        let mut with_dns_names = expected.clone();
+        with_dns_names.web_targets = web_targets
+            .into_iter()
+            .chain([
+                url("http://www.example.com/"),
+                url("http://www.example.com:8080/"),
+                url("https://www.example.com/"),
+                url("https://www.example.com:8000/"),
+                url("https://www.example.com:8080/"),
+            ])
+            .collect();
+        with_dns_names.web_targets.sort();
+        with_dns_names.hostnames =
+            [("www.example.com".into(), "192.0.2.10".parse().unwrap())]
+                .into_iter()
+                .collect();
+
+        for (dns_hostnames, expected) in
+            [(false, expected), (true, with_dns_names)]
+        {
+            eprintln!("Test case: {:?}", dns_hostnames);
+            let opts = Opts {
+                nessus: vec!["test/scan.nessus".into()],
+                dns_hostnames,
+                ..Default::default()
+            };
+            let parsed = generate_target_lists(&opts);
+            eprintln!("Parsed: {:?}", parsed);
+
+            assert_eq!(parsed, sourced(expected, "test/scan.nessus"));
         }
     }
 
@@ -1279,4 +2537,35 @@ mod test {
             assert_eq!(disp, case.1);
         }
     }
+
+    #[test]
+    fn target_host() {
+        let test_cases = vec![
+            (
+                Target::Url(Url::parse("https://[2001:db8::6]:8443/").unwrap()),
+                "2001:db8::6",
+            ),
+            (
+                Target::Url(Url::parse("http://example.com/path").unwrap()),
+                "example.com",
+            ),
+            (
+                Target::Address(
+                    "[::1]:3389".to_socket_addrs().unwrap().next().unwrap(),
+                ),
+                "::1",
+            ),
+            (
+                Target::Address(
+                    "192.0.2.1:5900".to_socket_addrs().unwrap().next().unwrap(),
+                ),
+                "192.0.2.1",
+            ),
+        ];
+
+        for (target, host) in test_cases {
+            eprintln!("Test case: {:?}", target);
+            assert_eq!(target.host(), host);
+        }
+    }
 }
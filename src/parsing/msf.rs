@@ -0,0 +1,121 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020-2021 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Import of the CSV files written by Metasploit's `services -o`
+//! command. Each row is a service from the Metasploit database, with
+//! host, port, proto, name, state and info columns. The service names
+//! come from Nmap, so the same rules are used to decide what to capture.
+
+use super::{lists_from_service, InputLists, Service};
+use crate::argparse::Opts;
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use std::io::BufRead;
+use std::net::IpAddr;
+
+/// Read the targets from a Metasploit services CSV file. Only open TCP
+/// services are used. The info column holds the product and version
+/// that Nmap found, so a service with info is treated as probed.
+pub fn lists_from_msf_csv(
+    reader: &mut dyn BufRead,
+    opts: &Opts,
+) -> Result<InputLists, String> {
+    // The info column can hold quoted newlines, so the file is read as
+    // CSV records rather than line by line
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let header = reader.headers().map_err(|e| e.to_string())?.clone();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|h| h == name)
+            .ok_or(format!("No {} column", name))
+    };
+    let host_col = column("host")?;
+    let port_col = column("port")?;
+    let proto_col = column("proto")?;
+    let name_col = column("name")?;
+    let state_col = column("state")?;
+    let info_col = header.iter().position(|h| h == "info");
+
+    let mut list: InputLists = Default::default();
+    for row in reader.records() {
+        let row = row.map_err(|e| e.to_string())?;
+        let field = |col: usize| row.get(col).unwrap_or_default();
+        debug!("Parsing service {:?}", row);
+
+        if field(proto_col) != "tcp" || field(state_col) != "open" {
+            trace!("Skipping service that is not open TCP: {:?}", row);
+            continue;
+        }
+        let addr: IpAddr = match field(host_col).parse() {
+            Ok(addr) => addr,
+            Err(e) => {
+                warn!("Error parsing host {:?}: {}", field(host_col), e);
+                continue;
+            }
+        };
+        let port: u16 = match field(port_col).parse() {
+            Ok(port) => port,
+            Err(e) => {
+                warn!("Error parsing port {:?}: {}", field(port_col), e);
+                continue;
+            }
+        };
+        let product = info_col.map(field).unwrap_or_default();
+        let service = Service {
+            port,
+            name: field(name_col),
+            product,
+            tunnel_ssl: false,
+            probed: !product.is_empty(),
+        };
+        lists_from_service(addr, &service, opts, &mut list);
+    }
+    Ok(list)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn multi_line_info() {
+        let content = concat!(
+            "host,port,proto,name,state,info\n",
+            "192.0.2.30,22,tcp,ssh,open,\"OpenSSH 8.2p1\n",
+            "Ubuntu \"\"4ubuntu0.5\"\"\"\n",
+            "192.0.2.30,3389,tcp,ms-wbt-server,open,\n",
+            "\n",
+            "192.0.2.30,5900,tcp,vnc,open,\"VNC, protocol 3.8\"\n",
+        );
+        let opts: Opts = Default::default();
+        let list = lists_from_msf_csv(&mut content.as_bytes(), &opts).unwrap();
+        let targets: Vec<String> = list
+            .rdp_targets
+            .iter()
+            .chain(&list.vnc_targets)
+            .map(|t| t.to_string())
+            .collect();
+        assert_eq!(targets, vec!["192.0.2.30:3389", "192.0.2.30:5900"]);
+        assert!(list.web_targets.is_empty());
+    }
+}
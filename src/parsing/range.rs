@@ -0,0 +1,311 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020-2021 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Expansion of CIDR blocks and address ranges into lists of individual
+//! addresses. Supported formats are:
+//! * CIDR blocks, e.g. 192.0.2.0/24 or 2001:db8::/120
+//! * Full ranges, e.g. 192.0.2.1-192.0.2.50 or 2001:db8::1-2001:db8::ff
+//! * Octet ranges, e.g. 192.0.2.1-50, 192.0.1-3.1-254, 192.0.2.1,3,5
+//!   or 192.0.2.*
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Try to expand the input as an address range. Returns None if the
+/// input does not look like a range at all, so that it can be parsed
+/// as a regular target instead. Ranges that are malformed or that would
+/// expand to more than `limit` addresses return an error.
+pub fn expand(
+    input: &str,
+    limit: usize,
+) -> Option<Result<Vec<IpAddr>, String>> {
+    // URLs can legitimately contain slashes and dashes
+    if input.contains("://") {
+        return None;
+    }
+
+    if let Some((addr, prefix)) = input.split_once('/') {
+        let addr: IpAddr = addr.parse().ok()?;
+        return Some(expand_cidr(addr, prefix, limit));
+    }
+
+    if let Some((start, end)) = input.split_once('-') {
+        if let (Ok(start), Ok(end)) =
+            (start.parse::<IpAddr>(), end.parse::<IpAddr>())
+        {
+            return Some(expand_full_range(start, end, limit));
+        }
+    }
+
+    if input.contains(['-', ',', '*']) {
+        return expand_octets(input, limit);
+    }
+
+    None
+}
+
+fn check_limit(count: u128, limit: usize) -> Result<(), String> {
+    if count > limit as u128 {
+        Err(format!(
+            "range contains {} addresses, which is more than the limit of {}",
+            count, limit
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn expand_cidr(
+    addr: IpAddr,
+    prefix: &str,
+    limit: usize,
+) -> Result<Vec<IpAddr>, String> {
+    let prefix: u32 = prefix
+        .parse()
+        .map_err(|_| format!("invalid prefix length \"{}\"", prefix))?;
+
+    match addr {
+        IpAddr::V4(a) => {
+            if prefix > 32 {
+                return Err(format!("invalid IPv4 prefix length {}", prefix));
+            }
+            let host_bits = 32 - prefix;
+            let count = 1_u128 << host_bits;
+            check_limit(count, limit)?;
+            let mask = u32::MAX.checked_shl(host_bits).unwrap_or(0);
+            let start = u32::from(a) & mask;
+            Ok((0..count as u32)
+                .map(|i| IpAddr::V4(Ipv4Addr::from(start + i)))
+                .collect())
+        }
+        IpAddr::V6(a) => {
+            if prefix > 128 {
+                return Err(format!("invalid IPv6 prefix length {}", prefix));
+            }
+            let host_bits = 128 - prefix;
+            // A /0 does not fit in a u128 count, but it is certainly
+            // over any sensible limit
+            let count = 1_u128.checked_shl(host_bits).unwrap_or(u128::MAX);
+            check_limit(count, limit)?;
+            let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+            let start = u128::from(a) & mask;
+            Ok((0..count)
+                .map(|i| IpAddr::V6(Ipv6Addr::from(start + i)))
+                .collect())
+        }
+    }
+}
+
+fn expand_full_range(
+    start: IpAddr,
+    end: IpAddr,
+    limit: usize,
+) -> Result<Vec<IpAddr>, String> {
+    match (start, end) {
+        (IpAddr::V4(s), IpAddr::V4(e)) => {
+            let (s, e) = (u32::from(s), u32::from(e));
+            if s > e {
+                return Err("range start is after range end".to_string());
+            }
+            check_limit((e - s) as u128 + 1, limit)?;
+            Ok((s..=e).map(|a| IpAddr::V4(Ipv4Addr::from(a))).collect())
+        }
+        (IpAddr::V6(s), IpAddr::V6(e)) => {
+            let (s, e) = (u128::from(s), u128::from(e));
+            if s > e {
+                return Err("range start is after range end".to_string());
+            }
+            check_limit((e - s).saturating_add(1), limit)?;
+            Ok((s..=e).map(|a| IpAddr::V6(Ipv6Addr::from(a))).collect())
+        }
+        _ => Err("range mixes IPv4 and IPv6 addresses".to_string()),
+    }
+}
+
+/// Parse a single octet specification such as "5", "1-50", "1,3,5" or
+/// "*". Returns None if the octet is not valid, in which case the input
+/// is most likely a hostname rather than a range.
+fn parse_octet(spec: &str) -> Option<Vec<u8>> {
+    if spec == "*" {
+        return Some((0..=255).collect());
+    }
+
+    let mut octets = Vec::new();
+    for part in spec.split(',') {
+        if let Some((start, end)) = part.split_once('-') {
+            let start: u8 = start.parse().ok()?;
+            let end: u8 = end.parse().ok()?;
+            if start > end {
+                return None;
+            }
+            octets.extend(start..=end);
+        } else {
+            octets.push(part.parse().ok()?);
+        }
+    }
+    Some(octets)
+}
+
+fn expand_octets(
+    input: &str,
+    limit: usize,
+) -> Option<Result<Vec<IpAddr>, String>> {
+    let parts: Vec<&str> = input.split('.').collect();
+    if parts.len() != 4 {
+        return None;
+    }
+    let octets = parts
+        .iter()
+        .map(|p| parse_octet(p))
+        .collect::<Option<Vec<_>>>()?;
+
+    let count: u128 = octets.iter().map(|o| o.len() as u128).product();
+    if let Err(e) = check_limit(count, limit) {
+        return Some(Err(e));
+    }
+
+    let mut addrs = Vec::with_capacity(count as usize);
+    for a in &octets[0] {
+        for b in &octets[1] {
+            for c in &octets[2] {
+                for d in &octets[3] {
+                    addrs.push(IpAddr::V4(Ipv4Addr::new(*a, *b, *c, *d)));
+                }
+            }
+        }
+    }
+    Some(Ok(addrs))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn v4(s: &str) -> IpAddr {
+        IpAddr::V4(s.parse().unwrap())
+    }
+
+    #[test]
+    fn not_a_range() {
+        let test_cases = vec![
+            "192.0.2.1",
+            "192.0.2.1:3389",
+            "2001:db8::1",
+            "[2001:db8::1]:8080",
+            "example.com",
+            "my-host.example.com",
+            "https://192.0.2.0/24",
+            "rdp://192.0.2.1",
+            "example.com/path",
+        ];
+
+        for case in test_cases {
+            eprintln!("Test case: {:?}", case);
+            assert!(expand(case, 1000).is_none());
+        }
+    }
+
+    #[test]
+    fn expand_ranges() {
+        let test_cases: Vec<(&str, Vec<IpAddr>)> = vec![
+            (
+                "192.0.2.0/30",
+                vec![
+                    v4("192.0.2.0"),
+                    v4("192.0.2.1"),
+                    v4("192.0.2.2"),
+                    v4("192.0.2.3"),
+                ],
+            ),
+            ("192.0.2.77/32", vec![v4("192.0.2.77")]),
+            (
+                // Host bits are ignored
+                "192.0.2.5/31",
+                vec![v4("192.0.2.4"), v4("192.0.2.5")],
+            ),
+            (
+                "2001:db8::10/127",
+                vec![
+                    "2001:db8::10".parse().unwrap(),
+                    "2001:db8::11".parse().unwrap(),
+                ],
+            ),
+            (
+                "192.0.2.254-192.0.3.1",
+                vec![
+                    v4("192.0.2.254"),
+                    v4("192.0.2.255"),
+                    v4("192.0.3.0"),
+                    v4("192.0.3.1"),
+                ],
+            ),
+            (
+                "2001:db8::1-2001:db8::2",
+                vec![
+                    "2001:db8::1".parse().unwrap(),
+                    "2001:db8::2".parse().unwrap(),
+                ],
+            ),
+            (
+                "10.0.0.1-3",
+                vec![v4("10.0.0.1"), v4("10.0.0.2"), v4("10.0.0.3")],
+            ),
+            (
+                "10.0.1-2.1,5",
+                vec![
+                    v4("10.0.1.1"),
+                    v4("10.0.1.5"),
+                    v4("10.0.2.1"),
+                    v4("10.0.2.5"),
+                ],
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            eprintln!("Test case: {:?}", input);
+            let expanded = expand(input, 1000).unwrap().unwrap();
+            assert_eq!(expanded, expected);
+        }
+
+        assert_eq!(expand("10.0.0.*", 1000).unwrap().unwrap().len(), 256);
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        let test_cases = vec![
+            "192.0.2.0/33",
+            "2001:db8::/129",
+            "192.0.2.0/x",
+            "192.0.2.50-192.0.2.1",
+            "192.0.2.1-2001:db8::1",
+            // Over the limit of 1000
+            "192.0.2.0/16",
+            "2001:db8::/64",
+            "::/0",
+            "10.0.*.*",
+            "10.0.0.0-10.0.255.255",
+        ];
+
+        for case in test_cases {
+            eprintln!("Test case: {:?}", case);
+            let result = expand(case, 1000);
+            eprintln!("Result: {:?}", result);
+            assert!(result.unwrap().is_err());
+        }
+    }
+}
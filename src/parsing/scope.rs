@@ -0,0 +1,405 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020-2021 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Scope allow-lists and deny-lists. Scope files have one entry per
+//! line, with blank lines and lines starting with # ignored:
+//! * addresses, CIDR blocks and full ranges, e.g. 192.0.2.1,
+//!   2001:db8::/64 or 192.0.2.1-192.0.2.50
+//! * hostnames, e.g. www.example.com, or *.example.com for any
+//!   subdomain
+//! * URL patterns, e.g. https://app.example.com/ for anything on that
+//!   site or https://*.example.com/admin*. The scheme, host, port and
+//!   path are matched separately: * in the host matches whole labels
+//!   and * in the path matches anything
+
+use super::Target;
+use regex::Regex;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use url::Url;
+
+#[derive(Debug)]
+enum Rule {
+    /// Inclusive range of addresses
+    Addresses(IpAddr, IpAddr),
+    Host(String),
+    /// Any subdomain of the domain
+    Subdomains(String),
+    Url(UrlPattern),
+}
+
+#[derive(Debug)]
+struct UrlPattern {
+    /// None for any scheme
+    scheme: Option<String>,
+    host: Regex,
+    /// None for any port. Without a port in the pattern this is the
+    /// default port of the scheme, if it has one.
+    port: Option<u16>,
+    /// Matched against the path and query of the target
+    path: Regex,
+}
+
+impl UrlPattern {
+    fn parse(entry: &str) -> Result<Self, String> {
+        let (scheme, rest) = entry.split_once("://").ok_or("not a URL")?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        // The colon of an IPv6 address is not a port separator
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority[i..].contains(']') => {
+                (&authority[..i], Some(&authority[i + 1..]))
+            }
+            _ => (authority, None),
+        };
+        if host.is_empty() {
+            return Err("a URL without a host".to_string());
+        }
+
+        let scheme = match scheme.to_lowercase() {
+            s if s == "*" => None,
+            s if Url::parse(&format!("{}://host/", s)).is_ok() => Some(s),
+            _ => return Err("a URL with an invalid scheme".to_string()),
+        };
+        let port = match port {
+            Some("*") => None,
+            Some(p) => {
+                Some(p.parse().map_err(|_| "a URL with an invalid port")?)
+            }
+            None => scheme.as_ref().and_then(|s| {
+                Url::parse(&format!("{}://host/", s))
+                    .ok()?
+                    .port_or_known_default()
+            }),
+        };
+
+        // A leading *. matches any subdomain, otherwise * matches a
+        // single label, never a dot or slash
+        let host = regex::escape(&host.to_lowercase());
+        let host = match host.strip_prefix(r"\*\.") {
+            Some(domain) => format!(r"(?:[^./]+\.)+{}", domain),
+            None => host,
+        };
+        let host = host.replace(r"\*", "[^./]+");
+        // Without a wildcard the path matches everything under it
+        let path = if path.contains('*') {
+            regex::escape(path).replace(r"\*", ".*")
+        } else {
+            format!("{}.*", regex::escape(path))
+        };
+
+        let anchored = |re: &str| {
+            Regex::new(&format!("(?i)^{}$", re)).map_err(|e| e.to_string())
+        };
+        Ok(UrlPattern {
+            scheme,
+            host: anchored(&host)?,
+            port,
+            path: anchored(&path)?,
+        })
+    }
+
+    fn matches(&self, u: &Url) -> bool {
+        if let Some(scheme) = &self.scheme {
+            if scheme != u.scheme() {
+                return false;
+            }
+        }
+        if self.port.is_some() && self.port != u.port_or_known_default() {
+            return false;
+        }
+        let path = match u.query() {
+            Some(query) => format!("{}?{}", u.path(), query),
+            None => u.path().to_string(),
+        };
+        self.host.is_match(u.host_str().unwrap_or_default())
+            && self.path.is_match(&path)
+    }
+}
+
+/// The first and last addresses of a CIDR block
+fn cidr_bounds(addr: IpAddr, prefix: &str) -> Result<(IpAddr, IpAddr), String> {
+    let prefix: u32 = prefix
+        .parse()
+        .map_err(|_| format!("invalid prefix length \"{}\"", prefix))?;
+    match addr {
+        IpAddr::V4(a) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            let start = u32::from(a) & mask;
+            let bounds = (Ipv4Addr::from(start), Ipv4Addr::from(start | !mask));
+            Ok((bounds.0.into(), bounds.1.into()))
+        }
+        IpAddr::V6(a) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            let start = u128::from(a) & mask;
+            let bounds = (Ipv6Addr::from(start), Ipv6Addr::from(start | !mask));
+            Ok((bounds.0.into(), bounds.1.into()))
+        }
+        _ => Err(format!("invalid prefix length {}", prefix)),
+    }
+}
+
+/// Whether the address lies in the inclusive range
+fn in_range(addr: IpAddr, start: IpAddr, end: IpAddr) -> bool {
+    match (addr, start, end) {
+        (IpAddr::V4(a), IpAddr::V4(s), IpAddr::V4(e)) => s <= a && a <= e,
+        (IpAddr::V6(a), IpAddr::V6(s), IpAddr::V6(e)) => s <= a && a <= e,
+        _ => false,
+    }
+}
+
+impl Rule {
+    fn parse(entry: &str) -> Result<Self, String> {
+        if entry.contains("://") {
+            return Ok(Rule::Url(UrlPattern::parse(entry)?));
+        }
+        if let Some((addr, prefix)) = entry.split_once('/') {
+            let addr = addr.parse().map_err(|_| "invalid address")?;
+            let (start, end) = cidr_bounds(addr, prefix)?;
+            return Ok(Rule::Addresses(start, end));
+        }
+        if let Some((start, end)) = entry.split_once('-') {
+            if let (Ok(start), Ok(end)) = (start.parse(), end.parse()) {
+                if !in_range(start, start, end) {
+                    return Err("range start is after range end".to_string());
+                }
+                return Ok(Rule::Addresses(start, end));
+            }
+        }
+        if let Ok(addr) = entry.parse() {
+            return Ok(Rule::Addresses(addr, addr));
+        }
+
+        let host = entry.to_lowercase();
+        let valid = |h: &str| {
+            !h.is_empty()
+                && h.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        };
+        match host.strip_prefix("*.") {
+            Some(domain) if valid(domain) => {
+                Ok(Rule::Subdomains(format!(".{}", domain)))
+            }
+            None if valid(&host) => Ok(Rule::Host(host)),
+            _ => Err("not an address, hostname or URL".to_string()),
+        }
+    }
+
+    fn matches(&self, target: &Target, addr: Option<IpAddr>) -> bool {
+        let host = target.host().to_lowercase();
+        let addrs = host.parse().ok().into_iter().chain(addr);
+        match self {
+            Rule::Addresses(start, end) => {
+                addrs.into_iter().any(|a| in_range(a, *start, *end))
+            }
+            Rule::Host(h) => &host == h,
+            Rule::Subdomains(suffix) => host.ends_with(suffix.as_str()),
+            Rule::Url(pattern) => match target {
+                Target::Url(u) => pattern.matches(u),
+                Target::Address(_) => false,
+            },
+        }
+    }
+}
+
+/// A list of scope entries loaded from one or more files
+#[derive(Debug, Default)]
+pub struct Scope {
+    entries: Vec<(String, Rule)>,
+}
+
+impl Scope {
+    fn parse(&mut self, content: &str, file: &str) -> Result<(), String> {
+        for (i, line) in content.lines().enumerate() {
+            let entry = line.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            let rule = Rule::parse(entry).map_err(|e| {
+                format!("{}:{}: {:?} is {}", file, i + 1, entry, e)
+            })?;
+            self.entries.push((entry.to_string(), rule));
+        }
+        Ok(())
+    }
+
+    /// Load the entries from each of the files
+    pub fn load(files: &[String]) -> Result<Self, String> {
+        let mut scope: Self = Default::default();
+        for file in files {
+            let content = fs::read_to_string(file)
+                .map_err(|e| format!("Error reading {}: {}", file, e))?;
+            scope.parse(&content, file)?;
+        }
+        Ok(scope)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether any entries match by address, so that targets using a
+    /// hostname need resolving to check against them
+    pub fn has_addresses(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|(_, rule)| matches!(rule, Rule::Addresses(..)))
+    }
+
+    /// The first entry that matches the target, or any of the addresses
+    /// that a target using a hostname connects to
+    pub fn find(&self, target: &Target, addrs: &[IpAddr]) -> Option<&str> {
+        let addrs: Vec<Option<IpAddr>> = std::iter::once(None)
+            .chain(addrs.iter().copied().map(Some))
+            .collect();
+        self.entries
+            .iter()
+            .find(|(_, rule)| addrs.iter().any(|&a| rule.matches(target, a)))
+            .map(|(entry, _)| entry.as_str())
+    }
+
+    /// Whether the target matches an entry, or every one of the
+    /// addresses that a target using a hostname connects to does
+    pub fn contains(&self, target: &Target, addrs: &[IpAddr]) -> bool {
+        let matched = |addr| {
+            self.entries
+                .iter()
+                .any(|(_, rule)| rule.matches(target, addr))
+        };
+        matched(None)
+            || (!addrs.is_empty() && addrs.iter().all(|&a| matched(Some(a))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::ToSocketAddrs;
+
+    #[test]
+    fn scope_entries() {
+        let mut scope: Scope = Default::default();
+        scope
+            .parse(
+                concat!(
+                    "# In scope\n",
+                    "192.0.2.0/28\n",
+                    "\n",
+                    "198.51.100.10-198.51.100.20\n",
+                    "2001:db8::/64\n",
+                    "www.example.com\n",
+                    "*.corp.example.com\n",
+                    "https://app.example.net\n",
+                    "http://*.example.org/admin*\n",
+                    "http://*.example.edu/*\n",
+                    "https://[2001:db8:1::8]:*/\n",
+                ),
+                "scope.txt",
+            )
+            .unwrap();
+
+        let addr = |s: &str| {
+            Target::Address(s.to_socket_addrs().unwrap().next().unwrap())
+        };
+        let url = |s: &str| Target::Url(Url::parse(s).unwrap());
+        let test_cases = vec![
+            (addr("192.0.2.15:3389"), None, Some("192.0.2.0/28")),
+            (addr("192.0.2.16:3389"), None, None),
+            (
+                url("https://198.51.100.20/"),
+                None,
+                Some("198.51.100.10-198.51.100.20"),
+            ),
+            (addr("[2001:db8::5]:5900"), None, Some("2001:db8::/64")),
+            (addr("[2001:db8:1::5]:5900"), None, None),
+            (
+                url("http://WWW.example.com/"),
+                None,
+                Some("www.example.com"),
+            ),
+            (url("http://example.com/"), None, None),
+            (
+                url("https://vpn.corp.example.com/"),
+                None,
+                Some("*.corp.example.com"),
+            ),
+            (url("https://corp.example.com/"), None, None),
+            (
+                url("https://app.example.net/login"),
+                None,
+                Some("https://app.example.net"),
+            ),
+            (url("http://app.example.net/"), None, None),
+            (
+                url("http://shop.example.org/admin/"),
+                None,
+                Some("http://*.example.org/admin*"),
+            ),
+            (url("http://shop.example.org/"), None, None),
+            (
+                url("http://a.b.example.edu/x"),
+                None,
+                Some("http://*.example.edu/*"),
+            ),
+            // Host wildcards must not match across the path
+            (url("http://evil.com/x.example.edu/"), None, None),
+            (url("http://example.edu.evil.com/"), None, None),
+            (url("http://a.example.edu:8080/"), None, None),
+            (
+                url("https://[2001:db8:1::8]:8443/"),
+                None,
+                Some("https://[2001:db8:1::8]:*/"),
+            ),
+            // A hostname that connects to an address in scope
+            (
+                url("https://intranet.example.net/"),
+                Some("192.0.2.1".parse().unwrap()),
+                Some("192.0.2.0/28"),
+            ),
+        ];
+
+        for (target, addr, expected) in test_cases {
+            eprintln!("Test case: {:?}", target.to_string());
+            let addrs: Vec<IpAddr> = addr.into_iter().collect();
+            assert_eq!(scope.find(&target, &addrs), expected);
+            assert_eq!(scope.contains(&target, &addrs), expected.is_some());
+        }
+
+        // A hostname is only in scope by address if all of its
+        // addresses are
+        let target = url("https://multi.example.net/");
+        let addrs =
+            ["192.0.2.1".parse().unwrap(), "203.0.113.1".parse().unwrap()];
+        assert_eq!(scope.find(&target, &addrs), Some("192.0.2.0/28"));
+        assert!(!scope.contains(&target, &addrs));
+
+        for entry in [
+            "192.0.2.0/33",
+            "192.0.2.9-192.0.2.1",
+            "not a host",
+            "http://:80/",
+            "http://example.com:http/",
+        ] {
+            eprintln!("Test case: {:?}", entry);
+            assert!(Rule::parse(entry).is_err());
+        }
+    }
+}
@@ -0,0 +1,322 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020-2021 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Import of the URLs found by web crawlers, directory brute-forcers
+//! and fuzzers. The format of each file is worked out from its content:
+//! * JSON from Dirble (an array of results), ffuf (a "results" array)
+//!   or feroxbuster (one object per line, with "type":"response" for
+//!   results), taking the status from "status", "status_code" or "code"
+//! * Burp site map XML, exported with "Save selected items"
+//! * CSV from ffuf, with "url" and "status_code" columns
+//! * Text from gobuster (with -e for full URLs, paths alone are skipped
+//!   with a warning) or feroxbuster, taking the first URL on each line
+//!   and its "Status:" or leading code
+
+use super::{InputLists, Target};
+use crate::argparse::{Mode, Opts};
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use serde_json::Value;
+use std::io::BufRead;
+use url::Url;
+
+/// Fields that hold the HTTP status in the JSON formats
+const STATUS_FIELDS: &[&str] = &["status", "status_code", "code"];
+
+/// A URL found by a crawler, with the status it returned if known
+#[derive(Debug, Eq, PartialEq)]
+struct Found {
+    url: Url,
+    status: Option<u16>,
+}
+
+/// Parse a web URL, ignoring anything that is not http or https
+fn web_url(s: &str) -> Option<Url> {
+    Url::parse(s.trim())
+        .ok()
+        .filter(|u| u.scheme() == "http" || u.scheme() == "https")
+}
+
+/// Collect the results from a JSON document: Dirble's top-level array,
+/// ffuf's "results" array or a feroxbuster "response" object. Nothing
+/// else is read, as ffuf's "config" holds the URL template with FUZZ in
+/// it rather than a result.
+fn from_json(value: &Value, found: &mut Vec<Found>) {
+    let results = match value {
+        Value::Array(values) => values.as_slice(),
+        Value::Object(map)
+            if map.get("type").and_then(Value::as_str) == Some("response") =>
+        {
+            std::slice::from_ref(value)
+        }
+        Value::Object(map) => match map.get("results") {
+            Some(Value::Array(values)) => values.as_slice(),
+            _ => return,
+        },
+        _ => return,
+    };
+    for map in results.iter().filter_map(Value::as_object) {
+        if let Some(url) =
+            map.get("url").and_then(Value::as_str).and_then(web_url)
+        {
+            let status = STATUS_FIELDS
+                .iter()
+                .find_map(|f| map.get(*f).and_then(Value::as_u64))
+                .and_then(|s| u16::try_from(s).ok());
+            found.push(Found { url, status });
+        }
+    }
+}
+
+/// Collect the items from a Burp site map. Burp's exports start with
+/// an inline DTD, which roxmltree refuses unless it is allowed.
+fn from_burp_xml(content: &str) -> Result<Vec<Found>, String> {
+    let options = roxmltree::ParsingOptions { allow_dtd: true };
+    let doc = roxmltree::Document::parse_with_options(content, options)
+        .map_err(|e| e.to_string())?;
+    let mut found = Vec::new();
+    for item in doc.descendants().filter(|n| n.has_tag_name("item")) {
+        let child_text = |name: &str| {
+            item.children()
+                .find(|c| c.has_tag_name(name))
+                .and_then(|c| c.text())
+        };
+        if let Some(url) = child_text("url").and_then(web_url) {
+            let status = child_text("status").and_then(|s| s.parse().ok());
+            found.push(Found { url, status });
+        }
+    }
+    Ok(found)
+}
+
+/// Collect the rows from ffuf's CSV output, or None if the content
+/// does not have a "url" column
+fn from_csv(content: &str) -> Option<Vec<Found>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(content.as_bytes());
+    let header = reader.headers().ok()?.clone();
+    let column = |name: &str| header.iter().position(|h| h == name);
+    let url_col = column("url")?;
+    let status_col = STATUS_FIELDS.iter().find_map(|f| column(f));
+
+    let mut found = Vec::new();
+    for row in reader.records() {
+        let row = match row {
+            Ok(row) => row,
+            Err(e) => {
+                warn!("Error parsing CSV row: {}", e);
+                continue;
+            }
+        };
+        if let Some(url) = row.get(url_col).and_then(web_url) {
+            let status = status_col
+                .and_then(|col| row.get(col))
+                .and_then(|s| s.parse().ok());
+            found.push(Found { url, status });
+        }
+    }
+    Some(found)
+}
+
+/// Find the URL and status on a line of text output, e.g.
+/// "http://192.0.2.1/admin (Status: 301) [Size: 178]" from gobuster or
+/// "200      GET       10l       20w      300c http://192.0.2.1/" from
+/// feroxbuster
+fn from_text_line(line: &str) -> Option<Found> {
+    let mut words = line.split_whitespace();
+    let url = words.clone().find_map(web_url)?;
+    let status = match line.find("Status: ") {
+        Some(i) => line[i + "Status: ".len()..]
+            .split(|c: char| !c.is_ascii_digit())
+            .next()
+            .and_then(|s| s.parse().ok()),
+        None => words
+            .next()
+            .and_then(|w| w.parse().ok())
+            .filter(|s| (100..600).contains(s)),
+    };
+    Some(Found { url, status })
+}
+
+/// Work out the format of the content and collect the URLs in it
+fn parse(content: &str) -> Result<Vec<Found>, String> {
+    let trimmed = content.trim_start();
+    if trimmed.starts_with('<') {
+        return from_burp_xml(content);
+    }
+
+    let mut found = Vec::new();
+    if trimmed.starts_with('[') || trimmed.starts_with('{') {
+        match serde_json::from_str::<Value>(content) {
+            Ok(value) => from_json(&value, &mut found),
+            // One JSON object per line
+            Err(_) => {
+                for line in content.lines().filter(|l| !l.trim().is_empty()) {
+                    match serde_json::from_str::<Value>(line) {
+                        Ok(value) => from_json(&value, &mut found),
+                        Err(e) => warn!("Error parsing {:?}: {}", line, e),
+                    }
+                }
+            }
+        }
+        return Ok(found);
+    }
+
+    if let Some(found) = from_csv(content) {
+        return Ok(found);
+    }
+
+    // gobuster only prints full URLs with -e, and a path alone does not
+    // say which site it was found on
+    let paths = content
+        .lines()
+        .filter(|l| l.trim_start().starts_with('/') && l.contains("(Status:"))
+        .count();
+    if paths > 0 {
+        warn!(
+            concat!(
+                "Skipping {} results that are paths rather than URLs,",
+                " re-run gobuster with -e to import them"
+            ),
+            paths
+        );
+    }
+    Ok(content.lines().filter_map(from_text_line).collect())
+}
+
+/// Read the web targets from a crawler, brute-forcer, fuzzer or Burp
+/// output file, keeping only those whose status matches
+/// `--url-status` if it is given
+pub fn lists_from_url_file(
+    reader: &mut dyn BufRead,
+    opts: &Opts,
+) -> Result<InputLists, String> {
+    let mut content = String::new();
+    reader
+        .read_to_string(&mut content)
+        .map_err(|e| e.to_string())?;
+
+    let mut list: InputLists = Default::default();
+    if !opts.mode.selected(Mode::Web) {
+        return Ok(list);
+    }
+    for found in parse(&content)? {
+        let wanted = opts.url_status.is_empty()
+            || found
+                .status
+                .filter(|s| {
+                    opts.url_status
+                        .iter()
+                        .any(|(low, high)| (*low..=*high).contains(s))
+                })
+                .is_some();
+        if wanted {
+            list.web_targets.push(Target::Url(found.url));
+        } else {
+            trace!("Skipping {} with status {:?}", found.url, found.status);
+        }
+    }
+    Ok(list)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crawler_formats() {
+        let found = |url: &str, status: Option<u16>| Found {
+            url: Url::parse(url).unwrap(),
+            status,
+        };
+        let test_cases = vec![
+            (
+                // Dirble
+                concat!(
+                    r#"[{"url":"http://192.0.2.1/admin/","code":200,"#,
+                    r#""size":10,"is_directory":true}]"#
+                ),
+                vec![found("http://192.0.2.1/admin/", Some(200))],
+            ),
+            (
+                // ffuf
+                concat!(
+                    r#"{"commandline":"ffuf","results":[{"input":"#,
+                    r#"{"FUZZ":"login"},"status":302,"#,
+                    r#""url":"https://192.0.2.1/login"}]}"#
+                ),
+                vec![found("https://192.0.2.1/login", Some(302))],
+            ),
+            (
+                // feroxbuster
+                concat!(
+                    r#"{"type":"response","url":"http://192.0.2.1/a","#,
+                    r#""status":403}"#,
+                    "\n",
+                    r#"{"type":"statistics","requests":100}"#
+                ),
+                vec![found("http://192.0.2.1/a", Some(403))],
+            ),
+            (
+                // ffuf CSV
+                concat!(
+                    "FUZZ,url,redirectlocation,position,status_code\n",
+                    "admin,http://192.0.2.1/admin,,1,401\n"
+                ),
+                vec![found("http://192.0.2.1/admin", Some(401))],
+            ),
+            (
+                // gobuster with -e
+                "http://192.0.2.1/images (Status: 301) [Size: 178]\n",
+                vec![found("http://192.0.2.1/images", Some(301))],
+            ),
+            (
+                // feroxbuster text
+                "200      GET        9l       29w      300c http://192.0.2.1/\n",
+                vec![found("http://192.0.2.1/", Some(200))],
+            ),
+            ("/images (Status: 301) [Size: 178]\n", vec![]),
+        ];
+
+        for (content, expected) in test_cases {
+            eprintln!("Test case: {:?}", content);
+            assert_eq!(parse(content).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn ffuf_config_is_not_a_result() {
+        let content = std::fs::read_to_string("test/ffuf.json").unwrap();
+        assert_eq!(
+            parse(&content).unwrap(),
+            vec![
+                Found {
+                    url: Url::parse("https://192.0.2.1/login").unwrap(),
+                    status: Some(302),
+                },
+                Found {
+                    url: Url::parse("https://192.0.2.1/admin").unwrap(),
+                    status: Some(401),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,108 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020-2021 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Limits on how fast targets are hit, shared by the RDP, VNC and web
+//! workers. Connection attempts are spaced out to a maximum number per
+//! second, optionally with a random extra delay. The limit on captures
+//! of a single host at once is applied by the scheduler.
+
+use crate::argparse::Opts;
+use rand::Rng;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct RateLimiter {
+    /// Minimum time between the starts of two attempts
+    interval: Option<Duration>,
+    /// Maximum random delay added before each attempt
+    jitter: Duration,
+    /// Earliest time at which the next attempt may start
+    next_start: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(opts: &Opts) -> Self {
+        Self {
+            interval: opts
+                .rate_limit
+                .map(|rate| Duration::from_secs_f64(1.0 / rate)),
+            jitter: Duration::from_millis(opts.jitter),
+            next_start: Mutex::new(None),
+        }
+    }
+
+    /// Reserve the next start time, returning how long to wait for it
+    fn reserve_start(&self, now: Instant) -> Duration {
+        let jitter = if self.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            rand::thread_rng().gen_range(Duration::ZERO..=self.jitter)
+        };
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return jitter,
+        };
+        let mut next_start = self.next_start.lock().unwrap();
+        let start = next_start.map_or(now, |t| t.max(now));
+        *next_start = Some(start + interval);
+        start - now + jitter
+    }
+
+    /// Block until the next connection attempt may start
+    pub fn wait(&self) {
+        thread::sleep(self.reserve_start(Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn connection_rate() {
+        let opts = Opts {
+            rate_limit: Some(4.0),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(&opts);
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+
+        // Starts are spaced 250ms apart however quickly they are asked for
+        assert_eq!(limiter.reserve_start(now), ms(0));
+        assert_eq!(limiter.reserve_start(now), ms(250));
+        assert_eq!(limiter.reserve_start(now + ms(100)), ms(400));
+        // After a quiet period there is no need to wait
+        assert_eq!(limiter.reserve_start(now + ms(5000)), ms(0));
+    }
+
+    #[test]
+    fn jitter() {
+        let opts = Opts {
+            jitter: 100,
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(&opts);
+        for _ in 0..20 {
+            let delay = limiter.reserve_start(Instant::now());
+            assert!(delay <= Duration::from_millis(100));
+        }
+    }
+}
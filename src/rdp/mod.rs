@@ -20,35 +20,78 @@
 use crate::argparse::Mode::Rdp;
 use crate::argparse::Opts;
 use crate::parsing::Target;
+use crate::ratelimit::RateLimiter;
 use crate::reporting::ReportMessageContent;
-use crate::reporting::{FileError, ReportMessage};
-use crate::util::target_to_filename;
-use crate::ThreadStatus;
+use crate::reporting::{FileError, Metadata, ReportMessage};
+use crate::socket::SocketType;
+use crate::util::{target_to_filename, with_retries};
 #[allow(unused)]
 use crate::{debug, error, info, trace, warn};
 use color_eyre::eyre::eyre;
 use image::{DynamicImage, ImageBuffer, Rgba};
 use rdp::core::client::{Connector, RdpClient};
 use rdp::core::event::RdpEvent;
-use socks::Socks5Stream;
+use rdp::model::error::Error as RdpError;
 use std::fmt::{self, Display, Formatter};
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::{ErrorKind, Read, Write};
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, mpsc::Receiver, mpsc::Sender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 pub enum Error {
     Rdp(String),
+    /// The host did not accept the connection in time
+    ConnectTimeout(String),
+    Timeout(String),
     Other(color_eyre::Report),
 }
 
+impl Error {
+    /// Short machine-readable name for the category of error
+    fn category(&self) -> &'static str {
+        match self {
+            Error::Rdp(_) => "rdp",
+            Error::ConnectTimeout(_) | Error::Timeout(_) => "timeout",
+            Error::Other(_) => "other",
+        }
+    }
+
+    /// Whether another attempt at the target might succeed, e.g. after
+    /// a stalled handshake or a busy server dropping the connection.
+    /// Hosts that do not accept the connection at all are most likely
+    /// down or filtered, so are not worth waiting for again.
+    fn is_transient(&self) -> bool {
+        const DROPPED: [&str; 2] = [
+            "failed to fill whole buffer",
+            "perhaps the server disconnected",
+        ];
+        match self {
+            Error::ConnectTimeout(_) => false,
+            Error::Timeout(_) => true,
+            Error::Rdp(e) => DROPPED.iter().any(|d| e.contains(d)),
+            Error::Other(e) => match e.downcast_ref::<std::io::Error>() {
+                Some(io) => matches!(
+                    io.kind(),
+                    ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                        | ErrorKind::BrokenPipe
+                        | ErrorKind::UnexpectedEof
+                ),
+                None => DROPPED.iter().any(|d| e.to_string().contains(d)),
+            },
+        }
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
         match self {
             Error::Rdp(e) => write!(fmt, "RDP error: {e}"),
+            Error::ConnectTimeout(e) | Error::Timeout(e) => {
+                write!(fmt, "Timed out: {e}")
+            }
             Error::Other(e) => write!(fmt, "{e}"),
         }
     }
@@ -234,49 +277,15 @@ impl Image {
     }
 }
 
-/// Wrapper enum to hold TCP and Socks5 streams. This enum implements
-/// Read and Write transitively
-enum SocketType {
-    Socks5(Socks5Stream),
-    Tcp(TcpStream),
-}
-
-impl Read for SocketType {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-        use SocketType::*;
-        match self {
-            Socks5(s) => s.read(buf),
-            Tcp(s) => s.read(buf),
-        }
-    }
-}
-
-impl Write for SocketType {
-    fn write(
-        &mut self,
-        buf: &[u8],
-    ) -> std::result::Result<usize, std::io::Error> {
-        use SocketType::*;
-        match self {
-            Socks5(s) => s.write(buf),
-            Tcp(s) => s.write(buf),
-        }
-    }
-    fn flush(&mut self) -> Result<(), std::io::Error> {
-        use SocketType::*;
-        match self {
-            Socks5(s) => s.flush(),
-            Tcp(s) => s.flush(),
-        }
-    }
-}
-
+/// Capture the target, returning the path of the saved image
 fn capture_worker(
     target: &Target,
     opts: &Opts,
-    report_tx: &mpsc::Sender<ReportMessage>,
-) -> Result<(), Error> {
+    metadata: &mut Metadata,
+) -> Result<String, Error> {
     info!(target, "Connecting to {:?}", target);
+    let deadline = Instant::now() + Duration::from_secs(opts.rdp_deadline);
+    let connect_timeout = Duration::from_secs(opts.rdp_connect_timeout);
     let addr = match target {
         Target::Address(sock_addr) => sock_addr,
         Target::Url(_) => {
@@ -288,13 +297,27 @@ fn capture_worker(
     // connection, otherwise create a regular TCP stream. The wrapper
     // enum is used to get around type errors and the limitation that
     // trait objects can only have one main trait (i.e. "dyn Read +
-    // Write") is not possible.
-    let stream = if let Some(proxy) = &opts.rdp_proxy {
+    // Write") is not possible. Once the handshake is over the socket's
+    // read timeout also ends the bitmap thread if the server goes quiet.
+    if opts.rdp_proxy.is_some() {
         debug!(target, "Connecting to Socks proxy");
-        SocketType::Socks5(Socks5Stream::connect(proxy, *addr)?)
-    } else {
-        SocketType::Tcp(TcpStream::connect(addr)?)
+    }
+    // Neither the connection nor the handshake may outlast the deadline
+    let bounded = |deadline: Instant| {
+        connect_timeout.min(deadline.saturating_duration_since(Instant::now()))
     };
+    let stream =
+        SocketType::connect(addr, opts.rdp_proxy.as_deref(), bounded(deadline))
+            .map_err(|e| match e.kind() {
+                // A SOCKS handshake that times out gives WouldBlock on Unix
+                ErrorKind::TimedOut | ErrorKind::WouldBlock => {
+                    Error::ConnectTimeout(format!(
+                        "Connection not established within {} seconds",
+                        opts.rdp_connect_timeout
+                    ))
+                }
+                _ => e.into(),
+            })?;
 
     debug!(target, "RDP domain: {:?}", opts.rdp_domain);
     debug!(target, "RDP username: {:?}", opts.rdp_user);
@@ -303,8 +326,10 @@ fn capture_worker(
     let mut connector = Connector::new()
         .screen(opts.size.0 as u16, opts.size.1 as u16)
         .check_certificate(false);
+    metadata.insert("screen_size", format!("{}x{}", opts.size.0, opts.size.1));
 
     if let (Some(user), Some(pass)) = (&opts.rdp_user, &opts.rdp_pass) {
+        metadata.insert("credentials", "supplied".to_string());
         connector = connector.credentials(
             opts.rdp_domain.as_ref().cloned().unwrap_or_default(),
             user.to_string(),
@@ -312,6 +337,7 @@ fn capture_worker(
         );
     } else {
         warn!(target, "Using blank RDP credentials");
+        metadata.insert("credentials", "blank".to_string());
         connector = connector.use_nla(false).blank_creds(true).credentials(
             "".to_string(),
             "".to_string(),
@@ -319,7 +345,35 @@ fn capture_worker(
         );
     };
 
-    let client = connector.connect(stream).map_err(|e| eyre!("{e:?}"))?;
+    // Bound the RDP/CredSSP handshake by whatever is left of the deadline
+    let handshake_timeout = bounded(deadline);
+    if handshake_timeout.is_zero() {
+        return Err(Error::ConnectTimeout(format!(
+            "Deadline of {} seconds reached before the RDP handshake",
+            opts.rdp_deadline
+        )));
+    }
+    stream.get_ref().set_read_timeout(Some(handshake_timeout))?;
+    stream
+        .get_ref()
+        .set_write_timeout(Some(handshake_timeout))?;
+
+    let client = connector.connect(stream).map_err(|e| match e {
+        // The handshake gets an io error if a socket timeout expires
+        RdpError::Io(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::TimedOut | ErrorKind::WouldBlock
+            ) =>
+        {
+            Error::Timeout(format!(
+                "RDP handshake stalled for more than {} seconds",
+                handshake_timeout.as_secs()
+            ))
+        }
+        RdpError::Io(e) => e.into(),
+        e => eyre!("{e:?}").into(),
+    })?;
 
     let mut rdp_image: Image = Default::default();
     {
@@ -331,9 +385,20 @@ fn capture_worker(
             bmp_thread(target_clone, client, bmp_sender);
         });
 
-        let timeout = Duration::from_secs(2);
+        // Keep receiving bitmaps until none have arrived for
+        // rdp_timeout seconds, or until the overall deadline so that a
+        // server that never stops drawing cannot hold the worker
+        let timeout = Duration::from_secs(opts.rdp_timeout as u64);
         loop {
-            match bmp_receiver.recv_timeout(timeout) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                warn!(
+                    target,
+                    "Deadline of {} seconds reached", opts.rdp_deadline
+                );
+                break;
+            }
+            match bmp_receiver.recv_timeout(timeout.min(remaining)) {
                 Err(_) => {
                     warn!(target, "Timeout reached");
                     break;
@@ -355,28 +420,19 @@ fn capture_worker(
             let filepath = Path::new(&opts.output_dir).join(&relative_filepath);
             info!(target, "Saving image as {}", filepath.display());
             di.extract().save(&filepath)?;
-            let report_message = ReportMessage::Output(ReportMessageContent {
-                mode: Rdp,
-                target: target.to_string(),
-                output: FileError::File(
-                    relative_filepath.display().to_string(),
-                ),
-            });
-            report_tx.send(report_message)?;
+            Ok(relative_filepath.display().to_string())
         }
         None => {
             warn!(target,
             "Error receiving image from {}. Perhaps the server disconnected",
             addr
             );
-            return Err(Error::Rdp(
+            Err(Error::Rdp(
                 "Error receiving image, perhaps the server disconnected"
                     .to_string(),
-            ));
+            ))
         }
     }
-
-    Ok(())
 }
 
 fn bmp_thread<T: Read + Write>(
@@ -444,35 +500,56 @@ fn bmp_thread<T: Read + Write>(
 pub fn capture(
     target: &Target,
     opts: &Opts,
-    tx: mpsc::Sender<ThreadStatus>,
     report_tx: &mpsc::Sender<ReportMessage>,
+    limiter: &RateLimiter,
 ) {
-    if let Err(e) = capture_worker(target, opts, report_tx) {
-        warn!(target, "error: {}", e);
-        let report_message = match &e {
-            Error::Rdp(r) if r.contains("failed to fill whole buffer") => {
-                ReportMessage::Output(ReportMessageContent {
-                    mode: Rdp,
-                    target: target.to_string(),
-                    output: FileError::Error(
-                        concat!(
+    let started = SystemTime::now();
+    let mut metadata = Metadata::new();
+    let (result, attempts) = with_retries(
+        target,
+        limiter,
+        opts.rdp_retries,
+        opts.rdp_backoff,
+        &mut metadata,
+        |metadata| capture_worker(target, opts, metadata),
+        |result, _| match result {
+            Err(e) if e.is_transient() => Some(e.to_string()),
+            _ => None,
+        },
+    );
+    let output = match result {
+        Ok(file) => FileError::File(file),
+        Err(e) => {
+            warn!(target, "error: {}", e);
+            match &e {
+                Error::Rdp(r) if r.contains("failed to fill whole buffer") => {
+                    FileError::Error {
+                        category: "disconnected",
+                        message: concat!(
                             "Unexpected disconnection, target may be XP-era ",
                             "which is currently unsupported"
                         )
                         .to_string(),
-                    ),
-                })
+                    }
+                }
+                _ => FileError::Error {
+                    category: e.category(),
+                    message: e.to_string(),
+                },
             }
-            _ => ReportMessage::Output(ReportMessageContent {
-                mode: Rdp,
-                target: target.to_string(),
-                output: FileError::Error(e.to_string()),
-            }),
-        };
-        report_tx
-            .send(report_message)
-            .expect("Reporting thread seems to have disconnected");
-    }
-
-    tx.send(ThreadStatus::Complete).unwrap();
+        }
+    };
+    let report_message = ReportMessage::Output(ReportMessageContent {
+        mode: Rdp,
+        target: target.to_string(),
+        output,
+        proxy: opts.rdp_proxy.clone(),
+        started,
+        duration: started.elapsed().unwrap_or_default(),
+        attempts,
+        metadata,
+    });
+    report_tx
+        .send(report_message)
+        .expect("Reporting thread seems to have disconnected");
 }
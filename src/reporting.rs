@@ -23,9 +23,15 @@ use crate::parsing::InputLists;
 
 use askama::Template;
 use color_eyre::Result;
-use std::fs;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::{mpsc, Arc};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::{Host, Url};
 
 #[allow(unused)]
 use log::{debug, error, info, trace, warn};
@@ -46,12 +52,21 @@ struct ReportTemplate {
 struct ReportItem {
     pub target: String,
     pub file: String,
+    pub proxy: Option<String>,
+    pub attempts: u32,
+    /// The same service reached by another hostname or address
+    pub related: Vec<String>,
+    pub tags: Vec<String>,
 }
 
 #[derive(Debug)]
 struct ReportError {
     pub target: String,
     pub error: String,
+    pub proxy: Option<String>,
+    pub attempts: u32,
+    pub related: Vec<String>,
+    pub tags: Vec<String>,
 }
 
 #[derive(Debug)]
@@ -65,29 +80,257 @@ pub struct ReportMessageContent {
     pub mode: Mode,
     pub target: String,
     pub output: FileError,
+    /// Proxy that the connection to the target was made through
+    pub proxy: Option<String>,
+    /// Time at which the capture of the target started
+    pub started: SystemTime,
+    /// Time taken to capture the target, or to fail
+    pub duration: Duration,
+    /// Number of attempts made at the target, including any retries
+    pub attempts: u32,
+    /// Protocol-specific details gathered during the capture, such as
+    /// the HTTP status or the VNC desktop name
+    pub metadata: Metadata,
 }
 
+pub type Metadata = BTreeMap<&'static str, String>;
+
 /// Capture the output status as either a file or an error
 #[derive(Debug)]
 pub enum FileError {
     File(String),
-    Error(String),
+    Error {
+        /// Short machine-readable category, e.g. "timeout" or "auth"
+        category: &'static str,
+        message: String,
+    },
+}
+
+/// File in the output directory that each result is appended to as
+/// soon as it arrives, so that an interrupted run can be resumed
+pub const STATE_FILE: &str = "state.jsonl";
+
+/// One record of results.json, describing the outcome for a single
+/// target. The same records are written line by line to the state file.
+/// The proxy URL without any username and password, which should not
+/// end up in the report or the results files
+fn redact_proxy(proxy: &str) -> String {
+    let (scheme, rest) = match proxy.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme), rest),
+        None => (None, proxy),
+    };
+    let host = rest.rsplit_once('@').map_or(rest, |(_, host)| host);
+    match scheme {
+        Some(scheme) => format!("{}://{}", scheme, host),
+        None => host.to_string(),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ResultRecord {
+    pub mode: String,
+    pub target: String,
+    /// The file or argument that the target was loaded from
+    source: Option<String>,
+    file: Option<String>,
+    error_category: Option<String>,
+    error: Option<String>,
+    proxy: Option<String>,
+    /// Seconds since the Unix epoch
+    started: u64,
+    duration_ms: u64,
+    attempts: u32,
+    metadata: BTreeMap<String, String>,
+    /// Tags given for the target in a target file
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl ResultRecord {
+    fn new(content: &ReportMessageContent, targets: &InputLists) -> Self {
+        let (file, error_category, error) = match &content.output {
+            FileError::File(file) => (Some(file.clone()), None, None),
+            FileError::Error { category, message } => {
+                (None, Some(category.to_string()), Some(message.clone()))
+            }
+        };
+
+        Self {
+            mode: content.mode.to_string(),
+            target: content.target.clone(),
+            source: targets.sources.get(&content.target).cloned(),
+            file,
+            error_category,
+            error,
+            proxy: content.proxy.as_deref().map(redact_proxy),
+            started: content
+                .started
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            duration_ms: content.duration.as_millis() as u64,
+            attempts: content.attempts,
+            metadata: content
+                .metadata
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+            tags: targets
+                .tags
+                .get(&content.target)
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether the target was captured successfully
+    pub fn succeeded(&self) -> bool {
+        self.file.is_some()
+    }
+}
+
+/// Parse the state file written by a previous run. Later records for a
+/// target replace earlier ones, and lines that cannot be parsed (e.g. a
+/// partial line left by a crash) are skipped.
+fn parse_state(reader: impl BufRead) -> io::Result<Vec<ResultRecord>> {
+    let mut records: BTreeMap<(String, String), ResultRecord> = BTreeMap::new();
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<ResultRecord>(&line) {
+            Ok(record) => {
+                records.insert(
+                    (record.mode.clone(), record.target.clone()),
+                    record,
+                );
+            }
+            Err(e) => {
+                warn!("Skipping line {} of {}: {}", idx + 1, STATE_FILE, e)
+            }
+        }
+    }
+    Ok(records.into_values().collect())
+}
+
+/// Load the results of a previous run from the output directory. A
+/// missing state file means there is nothing to resume.
+pub fn load_state(output_dir: &str) -> Result<Vec<ResultRecord>> {
+    let state_file = Path::new(output_dir).join(STATE_FILE);
+    match File::open(&state_file) {
+        Ok(f) => {
+            let records = parse_state(BufReader::new(f))?;
+            info!(
+                "Loaded {} previous results from {:?}",
+                records.len(),
+                state_file
+            );
+            Ok(records)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("No previous results found in {:?}", state_file);
+            Ok(Vec::new())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Open the state file for the records of this run. When resuming, the
+/// file is first rewritten from the records loaded from it, so that a
+/// partial line left by an interrupted run cannot swallow the first new
+/// record. The rewrite goes to a temporary file that replaces the state
+/// file, so the old state survives a crash part way through.
+fn open_state<'a>(
+    state_file: &Path,
+    resume: bool,
+    previous: impl Iterator<Item = &'a ResultRecord>,
+) -> io::Result<File> {
+    if resume {
+        let tmp_file = state_file.with_extension("jsonl.tmp");
+        let mut tmp = File::create(&tmp_file)?;
+        for record in previous {
+            let mut line = serde_json::to_string(record)?;
+            line.push('\n');
+            tmp.write_all(line.as_bytes())?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_file, state_file)?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resume)
+        .truncate(!resume)
+        .open(state_file)
+}
+
+const CSV_HEADER: [&str; 7] =
+    ["mode", "host", "port", "url", "status", "image", "error"];
+
+/// Stop a spreadsheet from treating a field as a formula. Page titles,
+/// URLs and error messages come from the targets, so a hostile server
+/// could otherwise get a formula run when results.csv is opened.
+fn csv_cell(field: &str) -> String {
+    if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", field)
+    } else {
+        field.to_string()
+    }
+}
+
+/// The fields of a row of results.csv from a target and either the path
+/// of its image or the error message
+fn csv_row(
+    mode: Mode,
+    target: &str,
+    output: Result<&str, &str>,
+) -> Vec<String> {
+    // RDP and VNC targets are socket addresses, web targets are URLs
+    let (host, port, url) = if let Ok(addr) = target.parse::<SocketAddr>() {
+        (addr.ip().to_string(), addr.port().to_string(), "")
+    } else if let Ok(u) = Url::parse(target) {
+        let host = match u.host() {
+            Some(Host::Ipv6(a)) => a.to_string(),
+            Some(h) => h.to_string(),
+            None => String::new(),
+        };
+        let port = u
+            .port_or_known_default()
+            .map(|p| p.to_string())
+            .unwrap_or_default();
+        (host, port, target)
+    } else {
+        (target.to_string(), String::new(), "")
+    };
+    let (status, image, error) = match output {
+        Ok(file) => ("captured", file, ""),
+        Err(e) => ("error", "", e),
+    };
+
+    [&mode.to_string(), &host, &port, url, status, image, error]
+        .iter()
+        .map(|f| csv_cell(f))
+        .collect()
 }
 
 pub fn reporting_thread(
     rx: mpsc::Receiver<ReportMessage>,
     opts: Arc<Opts>,
     targets: Arc<InputLists>,
+    previous: Vec<ResultRecord>,
 ) -> Result<()> {
     use Mode::*;
-    // Vecs to collect the output messages in
-    let mut rdp_outputs: Vec<ReportItem> = Vec::new();
-    let mut web_outputs: Vec<ReportItem> = Vec::new();
-    let mut vnc_outputs: Vec<ReportItem> = Vec::new();
+    // Results keyed by mode and target, starting from those of the run
+    // being resumed. A new result for a target replaces its old one.
+    let mut results: BTreeMap<(String, String), ResultRecord> = previous
+        .into_iter()
+        .map(|r| ((r.mode.clone(), r.target.clone()), r))
+        .collect();
 
-    let mut rdp_errors: Vec<ReportError> = Vec::new();
-    let mut web_errors: Vec<ReportError> = Vec::new();
-    let mut vnc_errors: Vec<ReportError> = Vec::new();
+    // Each result is written out as it arrives so that it survives the
+    // run being interrupted. Resuming appends to the existing state.
+    let state_file = Path::new(&opts.output_dir).join(STATE_FILE);
+    let mut state = open_state(&state_file, opts.resume, results.values())?;
 
     // Main loop listening on the channel
     while let Ok(msg) = rx.recv() {
@@ -97,58 +340,90 @@ pub fn reporting_thread(
             GenerateReport => break,
 
             Output(content) => {
-                match (content.output, content.mode) {
-                    (FileError::File(file), Rdp) => {
-                        rdp_outputs.push(ReportItem {
-                            target: content.target,
-                            file,
-                        });
-                    }
-                    (FileError::File(file), Web) => {
-                        web_outputs.push(ReportItem {
-                            target: content.target,
-                            file,
-                        });
-                    }
-                    (FileError::File(file), Vnc) => {
-                        vnc_outputs.push(ReportItem {
-                            target: content.target,
-                            file,
-                        });
-                    }
-                    (FileError::Error(error), Rdp) => {
-                        rdp_errors.push(ReportError {
-                            target: content.target,
-                            error,
-                        });
-                    }
-                    (FileError::Error(error), Web) => {
-                        web_errors.push(ReportError {
-                            target: content.target,
-                            error,
-                        });
-                    }
-                    (FileError::Error(error), Vnc) => {
-                        vnc_errors.push(ReportError {
-                            target: content.target,
-                            error,
-                        });
-                    }
-                    (_, Auto) => {
-                        // In theory there should never be an Auto making
-                        // it to this stage
-                        unreachable!()
-                    }
-                }
+                let record = ResultRecord::new(&content, &targets);
+                let mut line = serde_json::to_string(&record)?;
+                line.push('\n');
+                state.write_all(line.as_bytes())?;
+                results.insert(
+                    (record.mode.clone(), record.target.clone()),
+                    record,
+                );
+            }
+        }
+    }
+
+    // Vecs to collect the outputs for the report in
+    let mut rdp_outputs: Vec<ReportItem> = Vec::new();
+    let mut web_outputs: Vec<ReportItem> = Vec::new();
+    let mut vnc_outputs: Vec<ReportItem> = Vec::new();
+
+    let mut rdp_errors: Vec<ReportError> = Vec::new();
+    let mut web_errors: Vec<ReportError> = Vec::new();
+    let mut vnc_errors: Vec<ReportError> = Vec::new();
+
+    let related = targets.related_targets();
+    for record in results.values() {
+        let (outputs, errors) = match record.mode.parse() {
+            Ok(Rdp) => (&mut rdp_outputs, &mut rdp_errors),
+            Ok(Web) => (&mut web_outputs, &mut web_errors),
+            Ok(Vnc) => (&mut vnc_outputs, &mut vnc_errors),
+            _ => {
+                warn!("Ignoring result with unknown mode {:?}", record.mode);
+                continue;
             }
+        };
+        let related = related.get(&record.target).cloned().unwrap_or_default();
+        if let Some(file) = &record.file {
+            outputs.push(ReportItem {
+                target: record.target.clone(),
+                file: file.clone(),
+                proxy: record.proxy.clone(),
+                attempts: record.attempts,
+                related,
+                tags: record.tags.clone(),
+            });
+        } else {
+            errors.push(ReportError {
+                target: record.target.clone(),
+                error: record.error.clone().unwrap_or_default(),
+                proxy: record.proxy.clone(),
+                attempts: record.attempts,
+                related,
+                tags: record.tags.clone(),
+            });
         }
     }
 
+    // The JSON and CSV results are for other tools, so are written
+    // even when the HTML report is disabled
+    let results: Vec<&ResultRecord> = results.values().collect();
+    let results_file = Path::new(&opts.output_dir).join("results.json");
+    serde_json::to_writer_pretty(File::create(&results_file)?, &results)?;
+    info!("Results saved to {:?}", results_file);
+
+    let csv_file = Path::new(&opts.output_dir).join("results.csv");
+    let mut csv = csv::Writer::from_path(&csv_file)?;
+    csv.write_record(CSV_HEADER)?;
+    for (mode, outputs, errors) in [
+        (Rdp, &rdp_outputs, &rdp_errors),
+        (Web, &web_outputs, &web_errors),
+        (Vnc, &vnc_outputs, &vnc_errors),
+    ] {
+        for out in outputs {
+            csv.write_record(csv_row(mode, &out.target, Ok(&out.file)))?;
+        }
+        for err in errors {
+            csv.write_record(csv_row(mode, &err.target, Err(&err.error)))?;
+        }
+    }
+    csv.flush()?;
+    info!("CSV results saved to {:?}", csv_file);
+
     if !opts.disable_report {
         info!("Generating report");
 
-        println!("RDP outputs: {:?}", rdp_outputs);
-        println!("Web outputs: {:?}", web_outputs);
+        debug!("RDP outputs: {:?}", rdp_outputs);
+        debug!("Web outputs: {:?}", web_outputs);
 
         let report_file = Path::new(&opts.output_dir).join("report.html");
 
@@ -168,3 +443,218 @@ pub fn reporting_thread(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn csv_rows() {
+        use Mode::{Rdp, Vnc, Web};
+        let test_cases = vec![
+            (
+                Rdp,
+                "192.0.2.1:3389",
+                Ok("rdp/192.0.2.1-3389.png"),
+                "rdp,192.0.2.1,3389,,captured,rdp/192.0.2.1-3389.png,",
+            ),
+            (
+                Vnc,
+                "[2001:db8::1]:5900",
+                Err("Timed out: No complete frame received within 10 seconds"),
+                concat!(
+                    "vnc,2001:db8::1,5900,,error,,",
+                    "Timed out: No complete frame received within 10 seconds"
+                ),
+            ),
+            (
+                Web,
+                "https://[2001:db8::1]/",
+                Ok("web/https_2001_db8__1.png"),
+                concat!(
+                    "web,2001:db8::1,443,https://[2001:db8::1]/,captured,",
+                    "web/https_2001_db8__1.png,"
+                ),
+            ),
+            (
+                Web,
+                "http://example.com:8080/a,b",
+                Err("HTTP error: 500 \"Oops\""),
+                concat!(
+                    "web,example.com,8080,\"http://example.com:8080/a,b\",",
+                    "error,,\"HTTP error: 500 \"\"Oops\"\"\""
+                ),
+            ),
+            // Fields from the target must not become formulas
+            (
+                Web,
+                "http://192.0.2.1/",
+                Err("=HYPERLINK(\"http://evil.example/\")"),
+                concat!(
+                    "web,192.0.2.1,80,http://192.0.2.1/,error,,",
+                    "\"'=HYPERLINK(\"\"http://evil.example/\"\")\""
+                ),
+            ),
+            (
+                Web,
+                "http://192.0.2.1/",
+                Err("@SUM(1+1)"),
+                "web,192.0.2.1,80,http://192.0.2.1/,error,,'@SUM(1+1)",
+            ),
+        ];
+
+        for (mode, target, output, row) in test_cases {
+            eprintln!("Test case: {:?}", target);
+            let mut csv = csv::Writer::from_writer(Vec::new());
+            csv.write_record(csv_row(mode, target, output)).unwrap();
+            let written = String::from_utf8(csv.into_inner().unwrap()).unwrap();
+            assert_eq!(written, format!("{}\n", row));
+        }
+    }
+
+    #[test]
+    fn proxy_credentials() {
+        let test_cases = vec![
+            ("socks5://127.0.0.1:1080", "socks5://127.0.0.1:1080"),
+            ("socks5://user:pass@[::1]:1080", "socks5://[::1]:1080"),
+            (
+                "http://user:p@ss@proxy.example:8080",
+                "http://proxy.example:8080",
+            ),
+            ("user@proxy.example:1080", "proxy.example:1080"),
+        ];
+
+        for (proxy, expected) in test_cases {
+            eprintln!("Test case: {:?}", proxy);
+            assert_eq!(redact_proxy(proxy), expected);
+        }
+    }
+
+    #[test]
+    fn result_record_json() {
+        let mut targets: InputLists = Default::default();
+        targets
+            .sources
+            .insert("http://192.0.2.1/".into(), "targets.txt".into());
+        targets
+            .tags
+            .insert("http://192.0.2.1/".into(), vec!["dmz".into()]);
+
+        let content = ReportMessageContent {
+            mode: Mode::Web,
+            target: "http://192.0.2.1/".into(),
+            output: FileError::Error {
+                category: "http",
+                message: "HTTP error: 404 Not Found".into(),
+            },
+            proxy: None,
+            started: UNIX_EPOCH + Duration::from_secs(1667779200),
+            duration: Duration::from_millis(1500),
+            attempts: 3,
+            metadata: [("http_status", "404".to_string())].into(),
+        };
+
+        let record =
+            serde_json::to_value(ResultRecord::new(&content, &targets))
+                .unwrap();
+        assert_eq!(
+            record,
+            json!({
+                "mode": "web",
+                "target": "http://192.0.2.1/",
+                "source": "targets.txt",
+                "file": null,
+                "error_category": "http",
+                "error": "HTTP error: 404 Not Found",
+                "proxy": null,
+                "started": 1667779200,
+                "duration_ms": 1500,
+                "attempts": 3,
+                "metadata": {"http_status": "404"},
+                "tags": ["dmz"],
+            })
+        );
+    }
+
+    #[test]
+    fn resume_state() {
+        let state = concat!(
+            r#"{"mode":"rdp","target":"192.0.2.1:3389","source":null,"#,
+            r#""file":null,"error_category":"timeout","error":"Timed out","#,
+            r#""proxy":null,"started":1,"duration_ms":10,"attempts":1,"#,
+            r#""metadata":{}}"#,
+            "\n",
+            r#"{"mode":"web","target":"http://192.0.2.1/","source":null,"#,
+            r#""file":"web/http_192.0.2.1.png","error_category":null,"#,
+            r#""error":null,"proxy":null,"started":2,"duration_ms":20,"#,
+            r#""attempts":1,"metadata":{"http_status":"200"}}"#,
+            "\n\n",
+            r#"{"mode":"rdp","target":"192.0.2.1:3389","source":null,"#,
+            r#""file":"rdp/192.0.2.1-3389.png","error_category":null,"#,
+            r#""error":null,"proxy":null,"started":3,"duration_ms":30,"#,
+            r#""attempts":2,"metadata":{}}"#,
+            "\n",
+            // Partial line left behind by an interrupted run
+            r#"{"mode":"vnc","target":"192.0.2.1:5900","sou"#,
+        );
+
+        let records = parse_state(state.as_bytes()).unwrap();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|r| (r.mode.as_str(), r.target.as_str(), r.succeeded()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("rdp", "192.0.2.1:3389", true),
+                ("web", "http://192.0.2.1/", true),
+            ]
+        );
+        assert_eq!(records[0].started, 3);
+    }
+
+    #[test]
+    fn resume_truncated_state() {
+        let dir = std::env::temp_dir()
+            .join(format!("scrying-state-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let state_file = dir.join(STATE_FILE);
+        let record = |target: &str| -> ResultRecord {
+            serde_json::from_value(serde_json::json!({
+                "mode": "rdp",
+                "target": target,
+                "source": null,
+                "file": null,
+                "error_category": null,
+                "error": null,
+                "proxy": null,
+                "started": 1,
+                "duration_ms": 10,
+                "attempts": 1,
+                "metadata": {},
+            }))
+            .unwrap()
+        };
+
+        // A crash part way through writing the second record
+        let mut line =
+            serde_json::to_string(&record("192.0.2.1:3389")).unwrap();
+        line.push_str("\n{\"mode\":\"rdp\",\"tar");
+        fs::write(&state_file, line).unwrap();
+
+        let previous = load_state(dir.to_str().unwrap()).unwrap();
+        let mut state = open_state(&state_file, true, previous.iter()).unwrap();
+        let mut line =
+            serde_json::to_string(&record("192.0.2.2:3389")).unwrap();
+        line.push('\n');
+        state.write_all(line.as_bytes()).unwrap();
+        drop(state);
+
+        let records = load_state(dir.to_str().unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let targets: Vec<&str> =
+            records.iter().map(|r| r.target.as_str()).collect();
+        assert_eq!(targets, vec!["192.0.2.1:3389", "192.0.2.2:3389"]);
+    }
+}
@@ -0,0 +1,312 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020-2021 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! A fixed pool of worker threads that runs capture jobs for every
+//! protocol. Each protocol and each host can be limited to a number of
+//! concurrent jobs, and jobs that have not started yet are dropped once
+//! the cancellation flag is set, e.g. by ctrl-c.
+
+use crate::argparse::Mode;
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+
+/// How often to check the cancellation flag while waiting for jobs to
+/// complete
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<(Mode, String, Job)>,
+    running: HashMap<Mode, usize>,
+    limits: HashMap<Mode, usize>,
+    /// Number of jobs running against each host
+    hosts: HashMap<String, usize>,
+    max_per_host: Option<usize>,
+    shutdown: bool,
+}
+
+impl State {
+    /// Index of the first queued job whose protocol and host are both
+    /// below their limits. Jobs for busy hosts stay queued without
+    /// holding up a worker.
+    fn next_job(&self) -> Option<usize> {
+        self.queue.iter().position(|(mode, host, _)| {
+            let running = self.running.get(mode).copied().unwrap_or(0);
+            let on_host = self.hosts.get(host).copied().unwrap_or(0);
+            running < self.limits.get(mode).copied().unwrap_or(usize::MAX)
+                && on_host < self.max_per_host.unwrap_or(usize::MAX)
+        })
+    }
+
+    fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.running.values().all(|&n| n == 0)
+    }
+
+    fn cancel(&mut self) {
+        if !self.queue.is_empty() {
+            warn!("Cancelling {} captures", self.queue.len());
+            self.queue.clear();
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled whenever a job is queued or completes
+    changed: Condvar,
+    cancel: Arc<AtomicBool>,
+}
+
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    /// Start a pool of `workers` threads. Jobs are no longer started once
+    /// `cancel` is set.
+    pub fn new(workers: usize, cancel: Arc<AtomicBool>) -> Self {
+        let shared = Arc::new(Shared {
+            state: Default::default(),
+            changed: Condvar::new(),
+            cancel,
+        });
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || worker(&shared))
+            })
+            .collect();
+        Self { shared, workers }
+    }
+
+    /// Run at most `max` jobs for the given protocol at once
+    pub fn limit(&self, mode: Mode, max: usize) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.limits.insert(mode, max.max(1));
+    }
+
+    /// Run at most `max` jobs against a single host at once, or any
+    /// number if `max` is None
+    pub fn limit_per_host(&self, max: Option<usize>) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.max_per_host = max.map(|max| max.max(1));
+    }
+
+    /// Queue a job against the host to be run by the next free worker
+    pub fn submit(
+        &self,
+        mode: Mode,
+        host: String,
+        job: impl FnOnce() + Send + 'static,
+    ) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.queue.push_back((mode, host, Box::new(job)));
+        self.shared.changed.notify_one();
+    }
+
+    /// Block until every queued job has completed, or until the running
+    /// jobs have completed after cancellation, then stop the workers
+    pub fn wait(self) {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if self.shared.cancel.load(Ordering::SeqCst) {
+                state.cancel();
+            }
+            if state.is_idle() {
+                break;
+            }
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, CANCEL_POLL)
+                .unwrap()
+                .0;
+        }
+        state.shutdown = true;
+        drop(state);
+        self.shared.changed.notify_all();
+
+        for w in self.workers {
+            if w.join().is_err() {
+                error!("Worker thread finished with errors");
+            }
+        }
+    }
+}
+
+fn worker(shared: &Shared) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if state.shutdown {
+            return;
+        }
+        if shared.cancel.load(Ordering::SeqCst) {
+            state.cancel();
+        }
+        let idx = match state.next_job() {
+            Some(idx) => idx,
+            None => {
+                state = shared.changed.wait(state).unwrap();
+                continue;
+            }
+        };
+        let (mode, host, job) = state.queue.remove(idx).unwrap();
+        *state.running.entry(mode).or_default() += 1;
+        *state.hosts.entry(host.clone()).or_default() += 1;
+        drop(state);
+
+        // A panicking capture should not take the worker down with it
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("A {} capture panicked", mode);
+        }
+
+        state = shared.state.lock().unwrap();
+        if let Some(running) = state.running.get_mut(&mode) {
+            *running -= 1;
+        }
+        if let Some(on_host) = state.hosts.get_mut(&host) {
+            *on_host -= 1;
+            if *on_host == 0 {
+                state.hosts.remove(&host);
+            }
+        }
+        shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn per_protocol_limits() {
+        use Mode::{Rdp, Vnc};
+        let scheduler = Scheduler::new(8, Default::default());
+        scheduler.limit(Rdp, 2);
+        scheduler.limit(Vnc, 3);
+
+        let completed = Arc::new(AtomicUsize::new(0));
+        let rdp_peak = Arc::new(AtomicUsize::new(0));
+        let vnc_peak = Arc::new(AtomicUsize::new(0));
+        let rdp_running = Arc::new(AtomicUsize::new(0));
+        let vnc_running = Arc::new(AtomicUsize::new(0));
+
+        for i in 0..20 {
+            let (mode, running, peak) = if i % 2 == 0 {
+                (Rdp, rdp_running.clone(), rdp_peak.clone())
+            } else {
+                (Vnc, vnc_running.clone(), vnc_peak.clone())
+            };
+            let completed = completed.clone();
+            scheduler.submit(mode, format!("192.0.2.{i}"), move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(10));
+                running.fetch_sub(1, Ordering::SeqCst);
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        scheduler.wait();
+
+        assert_eq!(completed.load(Ordering::SeqCst), 20);
+        assert!(rdp_peak.load(Ordering::SeqCst) <= 2);
+        assert!(vnc_peak.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[test]
+    fn per_host_limit() {
+        use Mode::{Rdp, Vnc, Web};
+        let scheduler = Scheduler::new(8, Default::default());
+        scheduler.limit_per_host(Some(2));
+
+        let completed = Arc::new(AtomicUsize::new(0));
+        let busy_peak = Arc::new(AtomicUsize::new(0));
+        let busy_running = Arc::new(AtomicUsize::new(0));
+
+        // Most jobs are against one host, but the rest are not held up
+        // behind them
+        for (i, mode) in [Rdp, Vnc, Web].iter().cycle().take(12).enumerate() {
+            let host = if i < 9 { "192.0.2.1" } else { "192.0.2.2" };
+            let running = busy_running.clone();
+            let peak = busy_peak.clone();
+            let completed = completed.clone();
+            scheduler.submit(*mode, host.to_string(), move || {
+                if i < 9 {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                }
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        scheduler.wait();
+
+        assert_eq!(completed.load(Ordering::SeqCst), 12);
+        assert!(busy_peak.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn cancellation() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let scheduler = Scheduler::new(1, cancel.clone());
+        let completed = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..10 {
+            let cancel = cancel.clone();
+            let completed = completed.clone();
+            scheduler.submit(Mode::Rdp, "192.0.2.1".to_string(), move || {
+                // The first job to run cancels the rest
+                cancel.store(true, Ordering::SeqCst);
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        scheduler.wait();
+
+        assert_eq!(completed.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn panicking_job() {
+        let scheduler = Scheduler::new(1, Default::default());
+        let completed = Arc::new(AtomicUsize::new(0));
+
+        scheduler.submit(Mode::Vnc, "192.0.2.1".to_string(), || {
+            panic!("Capture failed")
+        });
+        let completed_clone = completed.clone();
+        scheduler.submit(Mode::Vnc, "192.0.2.1".to_string(), move || {
+            completed_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        scheduler.wait();
+
+        assert_eq!(completed.load(Ordering::SeqCst), 1);
+    }
+}
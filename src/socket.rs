@@ -0,0 +1,279 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020-2021 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! TCP connections to RDP and VNC targets, optionally made through a
+//! SOCKS5 proxy
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use url::Url;
+
+/// Wrapper enum to hold direct TCP streams and streams to a SOCKS5 proxy
+/// that has connected to the target. This enum implements Read and
+/// Write transitively
+pub enum SocketType {
+    Socks5(TcpStream),
+    Tcp(TcpStream),
+}
+
+/// Connect to the first of the addresses that accepts within the timeout
+fn connect_any(
+    addrs: impl ToSocketAddrs,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let mut error = io::Error::new(ErrorKind::NotFound, "No addresses");
+    for addr in addrs.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// Ask a SOCKS5 proxy to connect to the target over a stream that is
+/// already connected to the proxy, following RFC 1928. Only the "no
+/// authentication" method is offered.
+fn socks5_handshake(
+    stream: &mut TcpStream,
+    addr: &SocketAddr,
+) -> io::Result<()> {
+    let invalid = |e: &str| io::Error::new(ErrorKind::InvalidData, e);
+
+    stream.write_all(&[5, 1, 0])?;
+    let mut method = [0; 2];
+    stream.read_exact(&mut method)?;
+    match method {
+        [5, 0] => {}
+        [5, 0xff] => {
+            return Err(invalid("SOCKS5 proxy requires authentication"))
+        }
+        _ => return Err(invalid("Invalid SOCKS5 method selection")),
+    }
+
+    let mut request = vec![5, 1, 0];
+    match addr {
+        SocketAddr::V4(a) => {
+            request.push(1);
+            request.extend(a.ip().octets());
+        }
+        SocketAddr::V6(a) => {
+            request.push(4);
+            request.extend(a.ip().octets());
+        }
+    }
+    request.extend(addr.port().to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != 5 {
+        return Err(invalid("Invalid SOCKS5 reply"));
+    }
+    let kind = match reply[1] {
+        0 => None,
+        2 => Some((ErrorKind::PermissionDenied, "not allowed by ruleset")),
+        3 => Some((ErrorKind::Other, "network unreachable")),
+        4 => Some((ErrorKind::Other, "host unreachable")),
+        5 => Some((ErrorKind::ConnectionRefused, "connection refused")),
+        6 => Some((ErrorKind::TimedOut, "TTL expired")),
+        _ => Some((ErrorKind::Other, "general failure")),
+    };
+    if let Some((kind, e)) = kind {
+        return Err(io::Error::new(kind, format!("SOCKS5 proxy: {e}")));
+    }
+
+    // The address that the proxy bound is of no use, but has to be read
+    let len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => return Err(invalid("Invalid SOCKS5 bound address type")),
+    };
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound)
+}
+
+impl SocketType {
+    /// Connect to the target, through the SOCKS5 proxy if one is given.
+    /// The timeout applies to establishing the connection, to each step
+    /// of the SOCKS handshake and to every subsequent read and write on
+    /// the socket.
+    pub fn connect(
+        addr: &SocketAddr,
+        proxy: Option<&str>,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let stream = if let Some(proxy) = proxy {
+            let mut stream = connect_any(proxy_address(proxy)?, timeout)?;
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            socks5_handshake(&mut stream, addr)?;
+            SocketType::Socks5(stream)
+        } else {
+            SocketType::Tcp(TcpStream::connect_timeout(addr, timeout)?)
+        };
+
+        stream.get_ref().set_read_timeout(Some(timeout))?;
+        stream.get_ref().set_write_timeout(Some(timeout))?;
+        Ok(stream)
+    }
+
+    /// Get the underlying TCP stream, which for a proxied connection is
+    /// the one to the proxy
+    pub fn get_ref(&self) -> &TcpStream {
+        match self {
+            SocketType::Socks5(s) | SocketType::Tcp(s) => s,
+        }
+    }
+
+    /// Unwrap the TCP stream for libraries that can only use a
+    /// TcpStream. Once the SOCKS handshake is complete the proxy just
+    /// relays data, so this works for proxied connections too.
+    pub fn into_inner(self) -> TcpStream {
+        match self {
+            SocketType::Socks5(s) | SocketType::Tcp(s) => s,
+        }
+    }
+}
+
+impl Read for SocketType {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        use SocketType::*;
+        match self {
+            Socks5(s) | Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for SocketType {
+    fn write(
+        &mut self,
+        buf: &[u8],
+    ) -> std::result::Result<usize, std::io::Error> {
+        use SocketType::*;
+        match self {
+            Socks5(s) | Tcp(s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        use SocketType::*;
+        match self {
+            Socks5(s) | Tcp(s) => s.flush(),
+        }
+    }
+}
+
+/// Convert a proxy URI such as socks5://[::1]:1080 into the host:port
+/// form. The port defaults to 1080.
+fn proxy_address(proxy: &str) -> io::Result<String> {
+    let invalid = |e: &str| {
+        io::Error::new(ErrorKind::InvalidInput, format!("{e}: {proxy}"))
+    };
+    let u = Url::parse(proxy).map_err(|_| invalid("Invalid proxy URI"))?;
+    if u.scheme() != "socks5" {
+        return Err(invalid("Proxy must be a socks5:// URI"));
+    }
+    // host_str() keeps the square brackets around IPv6 addresses
+    let host = u.host_str().ok_or_else(|| invalid("Proxy has no host"))?;
+
+    Ok(format!("{}:{}", host, u.port().unwrap_or(1080)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn proxy_uri_to_address() {
+        let test_cases = vec![
+            ("socks5://127.0.0.1:9050", "127.0.0.1:9050"),
+            ("socks5://[::1]:1080/", "[::1]:1080"),
+            ("socks5://proxy.example", "proxy.example:1080"),
+        ];
+
+        for (proxy, address) in test_cases {
+            eprintln!("Test case: {:?}", proxy);
+            assert_eq!(proxy_address(proxy).unwrap(), address);
+        }
+
+        for proxy in ["http://127.0.0.1:8080", "127.0.0.1:1080"] {
+            eprintln!("Test case: {:?}", proxy);
+            assert!(proxy_address(proxy).is_err());
+        }
+    }
+
+    #[test]
+    fn socks5_connect() {
+        use std::net::TcpListener;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = format!("socks5://{}", listener.local_addr().unwrap());
+        let proxy_thread = thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            let mut greeting = [0; 3];
+            s.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            s.write_all(&[5, 0]).unwrap();
+            let mut request = [0; 10];
+            s.read_exact(&mut request).unwrap();
+            assert_eq!(request, [5, 1, 0, 1, 192, 0, 2, 1, 0x0d, 0x3d]);
+            s.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+            let mut relayed = [0; 5];
+            s.read_exact(&mut relayed).unwrap();
+            assert_eq!(&relayed, b"hello");
+        });
+
+        let addr = "192.0.2.1:3389".parse().unwrap();
+        let mut stream =
+            SocketType::connect(&addr, Some(&proxy), Duration::from_secs(5))
+                .unwrap();
+        stream.write_all(b"hello").unwrap();
+        proxy_thread.join().unwrap();
+    }
+
+    #[test]
+    fn socks5_handshake_timeout() {
+        use std::net::TcpListener;
+
+        // A proxy that accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = format!("socks5://{}", listener.local_addr().unwrap());
+        let addr = "192.0.2.1:3389".parse().unwrap();
+        let e = SocketType::connect(
+            &addr,
+            Some(&proxy),
+            Duration::from_millis(100),
+        )
+        .err()
+        .unwrap();
+        assert!(
+            matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock),
+            "{:?}",
+            e
+        );
+        drop(listener);
+    }
+}
@@ -18,7 +18,58 @@
 */
 
 use crate::parsing::Target;
+use crate::ratelimit::RateLimiter;
+use crate::reporting::Metadata;
+#[allow(unused)]
+use crate::{debug, error, info, trace, warn};
 use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+/// Longest delay between retries, unless the base backoff is longer
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Delay before the next attempt at a target after `attempts` failed
+/// ones. It starts at `backoff` and doubles after each attempt.
+pub fn backoff_delay(backoff: Duration, attempts: u32) -> Duration {
+    let factor = 1_u32 << attempts.saturating_sub(1).min(16);
+    backoff.saturating_mul(factor).min(MAX_BACKOFF.max(backoff))
+}
+
+/// Attempt a capture of the target up to `retries` more times while
+/// `retry` gives a reason to, waiting `backoff_ms` milliseconds after the
+/// first failure and doubling after each one. Every attempt waits for
+/// its turn under the connection rate limit, and the metadata is cleared
+/// before each one so that only the final attempt's is reported.
+/// Returns the final result and the number of attempts made.
+pub fn with_retries<T>(
+    target: &Target,
+    limiter: &RateLimiter,
+    retries: u32,
+    backoff_ms: u64,
+    metadata: &mut Metadata,
+    mut attempt: impl FnMut(&mut Metadata) -> T,
+    retry: impl Fn(&T, &Metadata) -> Option<String>,
+) -> (T, u32) {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        limiter.wait();
+        metadata.clear();
+        let result = attempt(metadata);
+        if attempts > retries {
+            return (result, attempts);
+        }
+        let reason = match retry(&result, metadata) {
+            Some(reason) => reason,
+            None => return (result, attempts),
+        };
+        let delay = backoff_delay(Duration::from_millis(backoff_ms), attempts);
+        warn!(target, "Attempt {} failed: {}", attempts, reason);
+        info!(target, "Retrying in {} ms", delay.as_millis());
+        thread::sleep(delay);
+    }
+}
 
 //TODO maybe move this to impl fmt::Display rather than a function
 pub fn target_to_filename(target: &Target) -> String {
@@ -121,4 +172,65 @@ mod test {
             assert_eq!(parsed, case.1);
         }
     }
+
+    #[test]
+    fn backoff_delays() {
+        let second = Duration::from_secs(1);
+        let test_cases = vec![
+            (second, 1, second),
+            (second, 2, 2 * second),
+            (second, 3, 4 * second),
+            (second, 7, 60 * second),
+            (second, 100, 60 * second),
+            (90 * second, 3, 90 * second),
+            (Duration::ZERO, 5, Duration::ZERO),
+        ];
+
+        for (backoff, attempts, expected) in test_cases {
+            eprintln!("Test case: {:?}", (backoff, attempts));
+            assert_eq!(backoff_delay(backoff, attempts), expected);
+        }
+    }
+
+    #[test]
+    fn retries() {
+        let target = Target::Address("192.0.2.1:3389".parse().unwrap());
+        let limiter = RateLimiter::new(&Default::default());
+        // retries, failures before success, attempts, success
+        let test_cases = vec![
+            (2, 0, 1, true),
+            (2, 1, 2, true),
+            (2, 5, 3, false),
+            (0, 1, 1, false),
+        ];
+
+        for case in test_cases {
+            eprintln!("Test case: {:?}", case);
+            let (retries, failures, expected_attempts, expected_ok) = case;
+            let mut metadata = Metadata::new();
+            let mut calls = 0;
+            let (result, attempts) = with_retries(
+                &target,
+                &limiter,
+                retries,
+                0,
+                &mut metadata,
+                |metadata| {
+                    // Nothing is kept from earlier attempts
+                    assert!(metadata.is_empty());
+                    calls += 1;
+                    metadata.insert("attempt", calls.to_string());
+                    if calls > failures {
+                        Ok(())
+                    } else {
+                        Err("failed")
+                    }
+                },
+                |result, _| result.err().map(String::from),
+            );
+            assert_eq!(attempts, expected_attempts);
+            assert_eq!(result.is_ok(), expected_ok);
+            assert_eq!(metadata["attempt"], attempts.to_string());
+        }
+    }
 }
@@ -20,23 +20,123 @@
 use crate::argparse::Mode::Vnc;
 use crate::argparse::Opts;
 use crate::parsing::Target;
+use crate::ratelimit::RateLimiter;
 use crate::reporting::ReportMessageContent;
-use crate::reporting::{FileError, ReportMessage};
-use crate::util::target_to_filename;
-use crate::ThreadStatus;
+use crate::reporting::{FileError, Metadata, ReportMessage};
+use crate::socket::SocketType;
+use crate::util::{target_to_filename, with_retries};
 #[allow(unused)]
 use crate::{debug, error, info, trace, warn};
-use color_eyre::{eyre::eyre, Result};
+use color_eyre::eyre::eyre;
 use image::{DynamicImage, ImageBuffer, Rgb};
 use std::cmp::min;
 use std::convert::TryInto;
-use std::net::TcpStream;
+use std::fmt::{self, Display, Formatter};
+use std::io::ErrorKind;
+use std::net::Shutdown;
 use std::path::Path;
 use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use vnc::client::{AuthChoice, AuthMethod, Client};
 use vnc::Colour;
 use vnc::{PixelFormat, Rect};
 
+pub enum Error {
+    Auth(String),
+    UnsupportedSecurity(String),
+    UnsupportedPixelFormat(String),
+    ConnectionRefused(String),
+    Network(String),
+    /// The host did not accept the connection in time
+    ConnectTimeout(String),
+    Timeout(String),
+    Other(color_eyre::Report),
+}
+
+impl Display for Error {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::Auth(e) => write!(fmt, "Authentication failed: {e}"),
+            Error::UnsupportedSecurity(e) => {
+                write!(fmt, "Unsupported security type: {e}")
+            }
+            Error::UnsupportedPixelFormat(e) => {
+                write!(fmt, "Unsupported pixel format: {e}")
+            }
+            Error::ConnectionRefused(e) => {
+                write!(fmt, "Connection refused: {e}")
+            }
+            Error::Network(e) => write!(fmt, "Network error: {e}"),
+            Error::ConnectTimeout(e) | Error::Timeout(e) => {
+                write!(fmt, "Timed out: {e}")
+            }
+            Error::Other(e) => write!(fmt, "{e}"),
+        }
+    }
+}
+
+impl Error {
+    /// Short machine-readable name for the category of error
+    fn category(&self) -> &'static str {
+        match self {
+            Error::Auth(_) => "auth",
+            Error::UnsupportedSecurity(_) => "unsupported_security",
+            Error::UnsupportedPixelFormat(_) => "unsupported_pixel_format",
+            Error::ConnectionRefused(_) => "connection_refused",
+            Error::Network(_) => "network",
+            Error::ConnectTimeout(_) | Error::Timeout(_) => "timeout",
+            Error::Other(_) => "other",
+        }
+    }
+
+    /// Whether another attempt at the target might succeed, e.g. after
+    /// a stalled handshake or the connection being dropped. Hosts that
+    /// do not accept the connection at all are not tried again.
+    fn is_transient(&self) -> bool {
+        matches!(self, Error::Network(_) | Error::Timeout(_))
+    }
+
+    fn from_io(e: std::io::Error) -> Self {
+        if is_timeout(&e) {
+            Error::Timeout(e.to_string())
+        } else if e.kind() == ErrorKind::ConnectionRefused {
+            Error::ConnectionRefused(e.to_string())
+        } else {
+            Error::Network(e.to_string())
+        }
+    }
+
+    fn from_vnc(e: vnc::Error) -> Self {
+        match e {
+            vnc::Error::Io(e) => Error::from_io(e),
+            vnc::Error::Disconnected => {
+                Error::Network("Server disconnected".to_string())
+            }
+            vnc::Error::AuthenticationFailure(e) => Error::Auth(e),
+            vnc::Error::AuthenticationUnavailable => {
+                Error::UnsupportedSecurity(
+                    "No supported security type offered".to_string(),
+                )
+            }
+            e => Error::Other(e.into()),
+        }
+    }
+}
+
+impl<E> From<E> for Error
+where
+    E: Into<color_eyre::Report>,
+{
+    fn from(e: E) -> Self {
+        Error::Other(e.into())
+    }
+}
+
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+}
+
 //TODO code reuse with RDP?
 struct Image {
     image: DynamicImage,
@@ -52,7 +152,11 @@ enum ColourFormat {
 }
 
 impl Image {
-    fn new(format: PixelFormat, width: u16, height: u16) -> Result<Self> {
+    fn new(
+        format: PixelFormat,
+        width: u16,
+        height: u16,
+    ) -> Result<Self, Error> {
         let image = match (format.depth, format.true_colour) {
             (15, true) | (16, true) | (24, true) => {
                 DynamicImage::ImageRgb8(ImageBuffer::<Rgb<u8>, Vec<u8>>::new(
@@ -68,9 +172,9 @@ impl Image {
                 height.into(),
             )),
             (d, t) => {
-                return Err(eyre!(
-                    "Invalid colour depth: {d}, true colour: {t}",
-                ))
+                return Err(Error::UnsupportedPixelFormat(format!(
+                    "colour depth {d}, true colour {t}",
+                )))
             }
         };
 
@@ -88,7 +192,7 @@ impl Image {
         target: &Target,
         rect: Rect,
         pixels: &[u8],
-    ) -> Result<()> {
+    ) -> Result<(), Error> {
         use ColourFormat::*;
         trace!(target, "pixels: {:?}", pixels);
         trace!(target, "rect: {:?}", rect);
@@ -116,10 +220,10 @@ impl Image {
             16 => 2,
             32 => 4,
             _ => {
-                return Err(eyre!(
-                    "Invalid bits per pixel: {}",
+                return Err(Error::UnsupportedPixelFormat(format!(
+                    "{} bits per pixel",
                     format.bits_per_pixel
-                ))
+                )))
             }
         };
         let mut idx = 0_usize;
@@ -144,7 +248,8 @@ impl Image {
                         } else {
                             return Err(eyre!(
                                 "Colour format mismatch: expected 8-bit colours",
-                            ));
+                            )
+                            .into());
                         }
                     }
                     DynamicImage::ImageRgb16(img) => {
@@ -157,7 +262,8 @@ impl Image {
                         } else {
                             return Err(eyre!(
                                 "Colour format mismatch: expected 16-bit colours",
-                            ));
+                            )
+                            .into());
                         }
                     }
 
@@ -261,7 +367,7 @@ impl Image {
         format: &PixelFormat,
         colour_map: &Option<ColourMap>,
         bytes: &[u8],
-    ) -> Result<ColourFormat> {
+    ) -> Result<ColourFormat, Error> {
         use ColourFormat::*;
         //TODO code reuse
         match (format.bits_per_pixel, format.depth) {
@@ -318,10 +424,12 @@ impl Image {
 
                     Ok(U16((r, g, b)))
                 } else {
-                    Err(eyre!("No colour map supplied for 8-bit mode!",))
+                    Err(eyre!("No colour map supplied for 8-bit mode!",).into())
                 }
             }
-            d => panic!("Unsupported colour depth {:?}", d),
+            (bpp, depth) => Err(Error::UnsupportedPixelFormat(format!(
+                "{bpp} bits per pixel with colour depth {depth}"
+            ))),
         }
     }
 
@@ -329,12 +437,12 @@ impl Image {
         &mut self,
         first_colour: u16,
         colours: Vec<Colour>,
-    ) -> Result<()> {
+    ) -> Result<(), Error> {
         if colours.len() != 256 {
-            return Err(eyre!(
-                "Invalid number of colours in map: {}",
+            return Err(Error::UnsupportedPixelFormat(format!(
+                "{} colours in colour map",
                 colours.len()
-            ));
+            )));
         }
         self.colour_map = Some(ColourMap {
             first_colour,
@@ -351,23 +459,50 @@ struct ColourMap {
     colours: Vec<Colour>,
 }
 
+/// Capture the target, returning the path of the saved image
 fn vnc_capture(
     target: &Target,
     opts: &Opts,
-    report_tx: &Sender<ReportMessage>,
-) -> Result<()> {
+    metadata: &mut Metadata,
+) -> Result<String, Error> {
     info!(target, "Connecting to {:?}", target);
     let addr = match target {
         Target::Address(sock_addr) => sock_addr,
         Target::Url(_) => {
-            return Err(eyre!("Invalid VNC target: {target}",));
+            return Err(eyre!("Invalid VNC target: {target}",).into());
         }
     };
 
-    let stream = TcpStream::connect(addr)?;
+    let connect_timeout = Duration::from_secs(opts.vnc_connect_timeout);
+    if opts.vnc_proxy.is_some() {
+        debug!(target, "Connecting to Socks proxy");
+    }
+    // The VNC client only accepts a TcpStream, so proxied connections
+    // are unwrapped once the SOCKS handshake is done
+    let stream =
+        SocketType::connect(addr, opts.vnc_proxy.as_deref(), connect_timeout)
+            .map_err(|e| {
+                if is_timeout(&e) {
+                    Error::ConnectTimeout(format!(
+                        "Connection not established within {} seconds",
+                        opts.vnc_connect_timeout
+                    ))
+                } else {
+                    Error::from_io(e)
+                }
+            })?
+            .into_inner();
+
+    // The client takes ownership of the stream, so keep a handle to the
+    // socket for changing its timeouts and shutting it down later
+    let control = stream.try_clone().map_err(Error::from_io)?;
 
-    let mut vnc = Client::from_tcp_stream(stream, false, |methods| {
+    // Record what the server offered so that a rejection can be
+    // reported meaningfully
+    let mut offered = String::new();
+    let vnc = Client::from_tcp_stream(stream, false, |methods| {
         debug!(target, "available auth methods: {:?}", methods);
+        offered = format!("{:?}", methods);
         // Turn off Clippy's single_match check because there might be
         // other auth methods in the future
         #[allow(clippy::single_match)]
@@ -390,7 +525,32 @@ fn vnc_capture(
         }
         warn!(target, "AuthMethod::None may not be supported");
         None
-    })?;
+    })
+    .map_err(|e| match e {
+        vnc::Error::Io(ref io) if is_timeout(io) => Error::Timeout(format!(
+            "VNC handshake stalled for more than {} seconds",
+            opts.vnc_connect_timeout
+        )),
+        vnc::Error::AuthenticationUnavailable => {
+            Error::UnsupportedSecurity(format!("Server offered {}", offered))
+        }
+        e => Error::from_vnc(e),
+    });
+    if !offered.is_empty() {
+        metadata.insert("auth_methods", offered);
+    }
+    let mut vnc = match vnc {
+        Ok(vnc) => vnc,
+        Err(e) => {
+            let _ = control.shutdown(Shutdown::Both);
+            return Err(e);
+        }
+    };
+
+    // The client now reads events on a background thread, which would
+    // treat a read timeout as a disconnection. Waiting for the frame is
+    // bounded by vnc_poll instead.
+    control.set_read_timeout(None).map_err(Error::from_io)?;
 
     // Server specifies the initial size, so just use that
     let (width, height) = vnc.size();
@@ -401,6 +561,8 @@ fn vnc_capture(
         width,
         height
     );
+    metadata.insert("desktop_name", vnc.name().to_string());
+    metadata.insert("framebuffer_size", format!("{}x{}", width, height));
 
     vnc.set_encodings(&[
         vnc::Encoding::Zrle,
@@ -408,10 +570,18 @@ fn vnc_capture(
         vnc::Encoding::Raw,
         vnc::Encoding::Cursor,
         vnc::Encoding::DesktopSize,
-    ])?;
+    ])
+    .map_err(Error::from_vnc)?;
 
     let vnc_format = vnc.format();
     debug!(target, "VNC pixel format: {:?}", vnc_format);
+    metadata.insert(
+        "pixel_format",
+        format!(
+            "{} bpp, depth {}, true colour {}",
+            vnc_format.bits_per_pixel, vnc_format.depth, vnc_format.true_colour
+        ),
+    );
 
     debug!(target, "requesting update");
     vnc.request_update(
@@ -422,11 +592,17 @@ fn vnc_capture(
             height,
         },
         false,
-    )?;
+    )
+    .map_err(Error::from_vnc)?;
 
     let mut vnc_image = Image::new(vnc_format, width, height)?;
 
-    vnc_poll(target, vnc, &mut vnc_image)?;
+    let frame_timeout = Duration::from_secs(opts.vnc_timeout);
+    let result = vnc_poll(target, vnc, &mut vnc_image, frame_timeout);
+    // Make sure that the client's reader thread does not outlive us if
+    // the server has gone quiet
+    let _ = control.shutdown(Shutdown::Both);
+    result?;
 
     // Save the image
     info!(target, "Successfully received image");
@@ -436,22 +612,17 @@ fn vnc_capture(
     info!(target, "Saving image as {}", filepath.display());
     vnc_image.image.save(&filepath)?;
 
-    let report_message = ReportMessage::Output(ReportMessageContent {
-        mode: Vnc,
-        target: target.to_string(),
-        output: FileError::File(relative_filepath.display().to_string()),
-    });
-    report_tx.send(report_message)?;
-
-    Ok(())
+    Ok(relative_filepath.display().to_string())
 }
 
 fn vnc_poll(
     target: &Target,
     mut vnc: Client,
     vnc_image: &mut Image,
-) -> Result<()> {
+    timeout: Duration,
+) -> Result<(), Error> {
     use vnc::client::Event::*;
+    let deadline = Instant::now() + timeout;
     loop {
         for event in vnc.poll_iter() {
             match event {
@@ -459,6 +630,9 @@ fn vnc_poll(
                     warn!(target, "VNC Channel disconnected");
                     return Ok(());
                 }
+                Disconnected(Some(e)) => {
+                    return Err(Error::from_vnc(e));
+                }
                 PutPixels(vnc_rect, ref pixels) => {
                     trace!(target, "PutPixels");
                     vnc_image.put_pixels(target, vnc_rect, pixels)?;
@@ -479,18 +653,95 @@ fn vnc_poll(
                 other => debug!(target, "Unsupported event: {:?}", other),
             }
         }
+
+        if Instant::now() >= deadline {
+            return Err(Error::Timeout(format!(
+                "No complete frame received within {} seconds",
+                timeout.as_secs()
+            )));
+        }
+        // poll_iter() does not block, so avoid spinning
+        thread::sleep(Duration::from_millis(10));
     }
 }
 
 pub fn capture(
     target: &Target,
     opts: &Opts,
-    tx: Sender<ThreadStatus>,
     report_tx: &Sender<ReportMessage>,
+    limiter: &RateLimiter,
 ) {
-    if let Err(e) = vnc_capture(target, opts, report_tx) {
-        warn!(target, "VNC error: {}", e);
+    let started = SystemTime::now();
+    let mut metadata = Metadata::new();
+    let (result, attempts) = with_retries(
+        target,
+        limiter,
+        opts.vnc_retries,
+        opts.vnc_backoff,
+        &mut metadata,
+        |metadata| vnc_capture(target, opts, metadata),
+        |result, _| match result {
+            Err(e) if e.is_transient() => Some(e.to_string()),
+            _ => None,
+        },
+    );
+    let output = match result {
+        Ok(file) => FileError::File(file),
+        Err(e) => {
+            warn!(target, "VNC error: {}", e);
+            FileError::Error {
+                category: e.category(),
+                message: e.to_string(),
+            }
+        }
+    };
+    let report_message = ReportMessage::Output(ReportMessageContent {
+        mode: Vnc,
+        target: target.to_string(),
+        output,
+        proxy: opts.vnc_proxy.clone(),
+        started,
+        duration: started.elapsed().unwrap_or_default(),
+        attempts,
+        metadata,
+    });
+    report_tx
+        .send(report_message)
+        .expect("Reporting thread seems to have disconnected");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn format(bits_per_pixel: u8, depth: u8) -> PixelFormat {
+        PixelFormat {
+            bits_per_pixel,
+            depth,
+            big_endian: false,
+            true_colour: true,
+            red_max: 255,
+            green_max: 255,
+            blue_max: 255,
+            red_shift: 16,
+            green_shift: 8,
+            blue_shift: 0,
+        }
     }
 
-    tx.send(ThreadStatus::Complete).unwrap();
+    #[test]
+    fn pixel_formats() {
+        let px = Image::pixel_to_rgb(&format(32, 24), &None, &[3, 2, 1, 0]);
+        assert!(matches!(px, Ok(ColourFormat::U8((1, 2, 3)))));
+
+        // 8-bit colour needs a colour map
+        let px = Image::pixel_to_rgb(&format(8, 8), &None, &[0]);
+        assert!(matches!(px, Err(Error::Other(_))));
+
+        for (bpp, depth) in [(32, 32), (16, 8), (24, 24)] {
+            eprintln!("Test case: {:?}", (bpp, depth));
+            let px = Image::pixel_to_rgb(&format(bpp, depth), &None, &[0; 4]);
+            assert!(matches!(px, Err(Error::UnsupportedPixelFormat(_))));
+        }
+    }
 }
@@ -1,28 +1,101 @@
-use super::save;
-use crate::{
-    argparse::Opts, parsing::Target, reporting::ReportMessage, InputLists,
+use super::{is_transient_status, save, Error};
+use crate::argparse::{Mode::Web, Opts};
+use crate::parsing::{InputLists, Target};
+use crate::ratelimit::RateLimiter;
+use crate::reporting::{
+    FileError, Metadata, ReportMessage, ReportMessageContent,
+};
+use crate::util::with_retries;
+#[allow(unused)]
+use crate::{debug, error, info, trace, warn};
+use chromiumoxide::cdp::browser_protocol::emulation::{
+    SetDeviceMetricsOverrideParams, SetTouchEmulationEnabledParams,
+};
+use chromiumoxide::cdp::browser_protocol::network::{
+    LoaderId, SetUserAgentOverrideParams,
 };
 use chromiumoxide::cdp::browser_protocol::page::{
-    CaptureScreenshotFormat, CaptureScreenshotParams,
+    CaptureScreenshotFormat, CaptureScreenshotParams, EventLifecycleEvent,
+    GetLayoutMetricsParams, NavigateParams, Viewport,
 };
-use chromiumoxide::{Browser, BrowserConfig};
+use chromiumoxide::{Browser, BrowserConfig, Page};
 use color_eyre::{eyre::eyre, Result};
-use futures::StreamExt;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    mpsc, Arc,
-};
+use futures::{future, StreamExt};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+use tokio::runtime::Handle;
+use tokio::time;
+use url::Url;
 
-pub async fn chrome_worker(
-    targets: Arc<InputLists>,
-    opts: Arc<Opts>,
-    report_tx: mpsc::Sender<ReportMessage>,
-    caught_ctrl_c: Arc<AtomicBool>,
-) -> Result<()> {
-    let (browser, mut handler) = Browser::launch(
-        BrowserConfig::builder().build().map_err(|e| eyre!(e))?,
-    )
-    .await?;
+/// User agent sent when emulating a mobile device
+const MOBILE_USER_AGENT: &str = concat!(
+    "Mozilla/5.0 (Linux; Android 13; Pixel 7) AppleWebKit/537.36",
+    " (KHTML, like Gecko) Chrome/107.0.0.0 Mobile Safari/537.36"
+);
+
+/// Convert a proxy URI such as http://[::1]:8080 or socks5://proxy:1080
+/// into the form expected by Chrome's --proxy-server argument
+fn proxy_server_arg(proxy: &str) -> Result<String> {
+    let u = Url::parse(proxy)?;
+    match u.scheme() {
+        "http" | "https" | "socks4" | "socks5" => {}
+        s => return Err(eyre!("Unsupported web proxy scheme: {s}")),
+    }
+    let host = u
+        .host_str()
+        .ok_or_else(|| eyre!("Web proxy has no host: {proxy}"))?;
+    // SOCKS proxies default to 1080, as they do for RDP and VNC
+    let port = match u.scheme() {
+        "socks4" | "socks5" => u.port().unwrap_or(1080),
+        _ => u
+            .port_or_known_default()
+            .ok_or_else(|| eyre!("Web proxy has no port: {proxy}"))?,
+    };
+
+    // host_str() keeps the square brackets around IPv6 addresses
+    Ok(format!("--proxy-server={}://{}:{}", u.scheme(), host, port))
+}
+
+/// Build Chrome's --host-resolver-rules argument so that hostnames from
+/// scan files resolve to the address that was scanned rather than
+/// whatever DNS returns, or None if there are no hostnames
+fn host_resolver_rules_arg(
+    hostnames: &BTreeMap<String, IpAddr>,
+) -> Option<String> {
+    if hostnames.is_empty() {
+        return None;
+    }
+    let rules: Vec<String> = hostnames
+        .iter()
+        .map(|(name, addr)| match addr {
+            IpAddr::V6(a) => format!("MAP {} [{}]", name, a),
+            IpAddr::V4(a) => format!("MAP {} {}", name, a),
+        })
+        .collect();
+    Some(format!("--host-resolver-rules={}", rules.join(", ")))
+}
+
+/// Start Chrome with the proxy and host resolver settings, and keep its
+/// event handler running on the async runtime
+pub async fn launch(targets: &InputLists, opts: &Opts) -> Result<Browser> {
+    let mut config = BrowserConfig::builder();
+    if let Some(proxy) = &opts.web_proxy {
+        info!("Web", "Using proxy {}", proxy);
+        // Chrome bypasses the proxy for loopback addresses by default,
+        // which is not what anyone routing through Burp wants
+        config = config
+            .arg(proxy_server_arg(proxy)?)
+            .arg("--proxy-bypass-list=<-loopback>");
+    }
+    if let Some(rules) = host_resolver_rules_arg(&targets.hostnames) {
+        debug!("Web", "Using {}", rules);
+        config = config.arg(rules);
+    }
+
+    let (browser, mut handler) =
+        Browser::launch(config.build().map_err(|e| eyre!(e))?).await?;
 
     let _handle = tokio::task::spawn(async move {
         loop {
@@ -30,25 +103,377 @@ pub async fn chrome_worker(
         }
     });
 
-    for target in &targets.web_targets {
-        if caught_ctrl_c.load(Ordering::SeqCst) {
-            break;
+    Ok(browser)
+}
+
+/// Capture the target in its own tab and report the result. This is
+/// run by the scheduler's worker threads, which use `runtime` to drive
+/// the browser.
+pub fn capture(
+    runtime: &Handle,
+    browser: &Browser,
+    target: &Target,
+    opts: &Opts,
+    report_tx: &mpsc::Sender<ReportMessage>,
+    limiter: &RateLimiter,
+) {
+    // one day we will have let-else chains
+    let u = if let Target::Url(u) = target {
+        u
+    } else {
+        return;
+    };
+    let started = SystemTime::now();
+    let mut metadata = Metadata::new();
+    let (result, attempts) = with_retries(
+        target,
+        limiter,
+        opts.web_retries,
+        opts.web_backoff,
+        &mut metadata,
+        |metadata| {
+            runtime.block_on(capture_page(browser, target, u, opts, metadata))
+        },
+        |result, metadata| match result {
+            Err(e) if e.is_transient() => Some(e.to_string()),
+            Err(_) => None,
+            // Overloaded servers are worth another go, but other error
+            // pages are captured like any other page
+            Ok(_) => metadata
+                .get("http_status")
+                .and_then(|s| s.parse().ok())
+                .filter(|&s| is_transient_status(s))
+                .map(|s| format!("HTTP status {}", s)),
+        },
+    );
+    let output = match result {
+        Ok(file) => FileError::File(file),
+        Err(e) => {
+            warn!(target, "{}", e);
+            FileError::Error {
+                category: e.category(),
+                message: e.to_string(),
+            }
+        }
+    };
+    let report_message = ReportMessage::Output(ReportMessageContent {
+        mode: Web,
+        target: target.to_string(),
+        output,
+        proxy: opts.web_proxy.clone(),
+        started,
+        duration: started.elapsed().unwrap_or_default(),
+        attempts,
+        metadata,
+    });
+    report_tx
+        .send(report_message)
+        .expect("Reporting thread seems to have disconnected");
+}
+
+/// Longest that a single attempt at a web target may take: one
+/// --web-timeout each for opening the tab, loading the page, each wait
+/// condition and taking the screenshot, plus the settle delay
+fn capture_timeout(opts: &Opts) -> Duration {
+    let steps =
+        3 + opts.web_wait_idle as u32 + opts.web_wait_selector.is_some() as u32;
+    Duration::from_secs(opts.web_timeout) * steps
+        + Duration::from_millis(opts.web_delay)
+}
+
+/// Open a new tab for the target, screenshot it, and close the tab
+/// again so that memory use does not grow with the number of targets.
+/// Returns the path of the saved image.
+async fn capture_page(
+    browser: &Browser,
+    target: &Target,
+    u: &Url,
+    opts: &Opts,
+    metadata: &mut Metadata,
+) -> Result<String, Error> {
+    // Bound the whole capture, as a browser that stops responding can
+    // stall steps that have no timeout of their own
+    let limit = capture_timeout(opts);
+    let deadline = time::Instant::now() + limit;
+    let timed_out = || {
+        Error::Timeout(format!(
+            "Capture did not complete within {} seconds",
+            limit.as_secs()
+        ))
+    };
+
+    info!(target, "Opening page");
+    let page = time::timeout_at(deadline, browser.new_page("about:blank"))
+        .await
+        .map_err(|_| timed_out())??;
+    let capture = async {
+        emulate_device(&page, opts).await?;
+        screenshot(&page, target, u, opts, metadata).await
+    };
+    let result = match time::timeout_at(deadline, capture).await {
+        Ok(result) => result,
+        Err(_) => Err(timed_out()),
+    };
+
+    // Close the tab even after a timeout so that it stops loading
+    let close = page.close();
+    match time::timeout(Duration::from_secs(opts.web_timeout), close).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            debug!(target, "Error closing page: {}", e);
+        }
+        Err(_) => {
+            debug!(target, "Timed out closing page");
         }
+    }
+    result
+}
+
+/// Set the viewport size, and the user agent and touch support if a
+/// mobile device is being emulated
+async fn emulate_device(page: &Page, opts: &Opts) -> Result<(), Error> {
+    let (width, height) = opts.web_size;
+    page.execute(SetDeviceMetricsOverrideParams::new(
+        width as i64,
+        height as i64,
+        1.0,
+        opts.web_mobile,
+    ))
+    .await?;
+
+    if opts.web_mobile {
+        page.execute(SetTouchEmulationEnabledParams::new(true))
+            .await?;
+        page.set_user_agent(SetUserAgentOverrideParams::new(MOBILE_USER_AGENT))
+            .await?;
+    }
 
-        // one day we will have let-else chains
-        let u = if let Target::Url(target) = target {
-            target
-        } else {
-            continue;
-        };
-        let page = browser.new_page(u.as_str()).await?;
-        page.wait_for_navigation().await?;
-        let params = CaptureScreenshotParams::builder()
-            .format(CaptureScreenshotFormat::Png)
-            .build();
-        let img = page.screenshot(params).await?;
-        save(target, &opts.output_dir, &img, &report_tx)?;
-    }
-    //handle.await?;
     Ok(())
 }
+
+/// Navigate to the URL and wait for the main response, returning the
+/// loader ID of the new document
+async fn navigate(
+    page: &Page,
+    target: &Target,
+    u: &Url,
+    metadata: &mut Metadata,
+) -> Result<Option<LoaderId>, Error> {
+    // Navigate explicitly rather than passing the URL to new_page() so
+    // that Chrome's error text for failed navigations is available
+    let navigation = page
+        .execute(NavigateParams::new(u.as_str()))
+        .await
+        .map_err(Error::from_cdp)?;
+    if let Some(error_text) = navigation.result.error_text {
+        return Err(Error::from_net_error(error_text));
+    }
+
+    let request = page
+        .wait_for_navigation_response()
+        .await
+        .map_err(Error::from_cdp)?;
+    if let Some(response) = request.as_ref().and_then(|r| r.response.as_ref()) {
+        debug!(target, "HTTP status {}", response.status);
+        metadata.insert("http_status", response.status.to_string());
+    }
+
+    Ok(navigation.result.loader_id.clone())
+}
+
+/// Poll the page until an element matching the selector exists
+async fn wait_for_selector(page: &Page, selector: &str) {
+    while page.find_element(selector).await.is_err() {
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn screenshot(
+    page: &Page,
+    target: &Target,
+    u: &Url,
+    opts: &Opts,
+    metadata: &mut Metadata,
+) -> Result<String, Error> {
+    let timeout = Duration::from_secs(opts.web_timeout);
+
+    // Subscribe before navigating so that no lifecycle events are missed
+    let lifecycle_events = if opts.web_wait_idle {
+        Some(page.event_listener::<EventLifecycleEvent>().await?)
+    } else {
+        None
+    };
+
+    let loader_id = time::timeout(timeout, navigate(page, target, u, metadata))
+        .await
+        .map_err(|_| {
+            Error::Timeout(format!(
+                "Navigation did not complete within {} seconds",
+                opts.web_timeout
+            ))
+        })??;
+
+    if let Some(events) = lifecycle_events {
+        debug!(target, "Waiting for network idle");
+        let idle = events.any(|e| {
+            future::ready(
+                e.name == "networkIdle"
+                    && (loader_id.is_none()
+                        || loader_id.as_ref() == Some(&e.loader_id)),
+            )
+        });
+        // Pages that poll forever never go idle, so capture them as
+        // they are rather than failing
+        if time::timeout(timeout, idle).await.is_err() {
+            warn!(
+                target,
+                "Network did not go idle within {} seconds, capturing anyway",
+                opts.web_timeout
+            );
+        }
+    }
+
+    if let Some(selector) = &opts.web_wait_selector {
+        debug!(target, "Waiting for selector {}", selector);
+        time::timeout(timeout, wait_for_selector(page, selector))
+            .await
+            .map_err(|_| {
+                Error::Timeout(format!(
+                    "Selector {} not found within {} seconds",
+                    selector, opts.web_timeout
+                ))
+            })?;
+    }
+
+    if opts.web_delay > 0 {
+        trace!(
+            target,
+            "Waiting {} ms for the page to settle",
+            opts.web_delay
+        );
+        time::sleep(Duration::from_millis(opts.web_delay)).await;
+    }
+
+    let mut params =
+        CaptureScreenshotParams::builder().format(CaptureScreenshotFormat::Png);
+    if opts.web_full_page {
+        // Clip to the size of the whole document rather than the
+        // viewport, which Chrome will render beyond the visible area
+        let metrics = page.execute(GetLayoutMetricsParams::default()).await?;
+        let content = &metrics.result.css_content_size;
+        debug!(
+            target,
+            "Full page size {}x{}", content.width, content.height
+        );
+        params = params.capture_beyond_viewport(true).clip(Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: content.width,
+            height: content.height,
+            scale: 1.0,
+        });
+    }
+    let params = params.build();
+    let img = page.screenshot(params).await?;
+
+    if let Ok(Some(title)) = page.get_title().await {
+        metadata.insert("title", title);
+    }
+    if let Ok(Some(url)) = page.url().await {
+        metadata.insert("final_url", url);
+    }
+
+    Ok(save(target, &opts.output_dir, &img)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capture_timeouts() {
+        let opts = Opts {
+            web_timeout: 30,
+            ..Default::default()
+        };
+        let test_cases = vec![
+            (opts.clone(), 90),
+            (
+                Opts {
+                    web_wait_idle: true,
+                    web_delay: 5000,
+                    ..opts.clone()
+                },
+                125,
+            ),
+            (
+                Opts {
+                    web_wait_idle: true,
+                    web_wait_selector: Some("#app".to_string()),
+                    ..opts.clone()
+                },
+                150,
+            ),
+        ];
+
+        for (opts, expected) in test_cases {
+            eprintln!("Test case: {:?}", opts.web_wait_selector);
+            assert_eq!(capture_timeout(&opts), Duration::from_secs(expected));
+        }
+    }
+
+    #[test]
+    fn resolver_rules_arg() {
+        assert_eq!(host_resolver_rules_arg(&BTreeMap::new()), None);
+
+        let hostnames = [
+            ("www.example.com".to_string(), "192.0.2.1".parse().unwrap()),
+            ("v6.example.com".to_string(), "2001:db8::1".parse().unwrap()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            host_resolver_rules_arg(&hostnames).unwrap(),
+            concat!(
+                "--host-resolver-rules=MAP v6.example.com [2001:db8::1],",
+                " MAP www.example.com 192.0.2.1"
+            )
+        );
+    }
+
+    #[test]
+    fn proxy_to_chrome_arg() {
+        let test_cases = vec![
+            (
+                "http://127.0.0.1:8080",
+                "--proxy-server=http://127.0.0.1:8080",
+            ),
+            ("http://[::1]:8080/", "--proxy-server=http://[::1]:8080"),
+            (
+                "http://proxy.example",
+                "--proxy-server=http://proxy.example:80",
+            ),
+            (
+                "https://[2001:db8::1]",
+                "--proxy-server=https://[2001:db8::1]:443",
+            ),
+            ("socks5://[::1]:1080", "--proxy-server=socks5://[::1]:1080"),
+            ("socks5://[::1]", "--proxy-server=socks5://[::1]:1080"),
+            ("socks4://pivot", "--proxy-server=socks4://pivot:1080"),
+            (
+                "socks5://192.0.2.1:9050",
+                "--proxy-server=socks5://192.0.2.1:9050",
+            ),
+        ];
+
+        for (proxy, arg) in test_cases {
+            eprintln!("Test case: {:?}", proxy);
+            assert_eq!(proxy_server_arg(proxy).unwrap(), arg);
+        }
+
+        for proxy in ["ftp://192.0.2.1:21", "127.0.0.1:8080"] {
+            eprintln!("Test case: {:?}", proxy);
+            assert!(proxy_server_arg(proxy).is_err());
+        }
+    }
+}
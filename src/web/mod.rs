@@ -17,26 +17,133 @@
  *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::argparse::Mode::Web;
 use crate::parsing::Target;
-use crate::reporting::{FileError, ReportMessage, ReportMessageContent};
 use crate::util::target_to_filename;
 #[allow(unused)]
 use crate::{debug, error, info, trace, warn};
+use chromiumoxide::error::CdpError;
 use color_eyre::Result;
+use std::fmt::{self, Display, Formatter};
 use std::path::Path;
-use std::sync::mpsc;
 use std::{fs::File, io::Write};
 
-pub use chrome::chrome_worker;
+pub use chrome::{capture, launch};
 mod chrome;
 
+/// Categories of failure for a single web capture
+pub enum Error {
+    Dns(String),
+    ConnectionRefused(String),
+    Tls(String),
+    /// The host did not accept the connection in time
+    ConnectTimeout(String),
+    Timeout(String),
+    Navigation(String),
+    Other(color_eyre::Report),
+}
+
+impl Error {
+    /// Classify a Chrome network error such as net::ERR_NAME_NOT_RESOLVED
+    fn from_net_error(error_text: String) -> Self {
+        let code = error_text.trim_start_matches("net::");
+        if code.starts_with("ERR_NAME_") || code.starts_with("ERR_DNS_") {
+            Error::Dns(error_text)
+        } else if code == "ERR_CONNECTION_REFUSED" {
+            Error::ConnectionRefused(error_text)
+        } else if code.starts_with("ERR_SSL_")
+            || code.starts_with("ERR_CERT_")
+            || code.starts_with("ERR_TLS_")
+            || code == "ERR_BAD_SSL_CLIENT_AUTH_CERT"
+        {
+            Error::Tls(error_text)
+        } else if code == "ERR_CONNECTION_TIMED_OUT" {
+            Error::ConnectTimeout(error_text)
+        } else if code == "ERR_TIMED_OUT" {
+            Error::Timeout(error_text)
+        } else {
+            Error::Navigation(error_text)
+        }
+    }
+
+    fn from_cdp(e: CdpError) -> Self {
+        match e {
+            CdpError::Timeout => Error::Timeout(e.to_string()),
+            e => Error::Other(e.into()),
+        }
+    }
+}
+
+impl Error {
+    /// Short machine-readable name for the category of error
+    fn category(&self) -> &'static str {
+        use Error::*;
+        match self {
+            Dns(_) => "dns",
+            ConnectionRefused(_) => "connection_refused",
+            Tls(_) => "tls",
+            ConnectTimeout(_) | Timeout(_) => "timeout",
+            Navigation(_) => "navigation",
+            Other(_) => "other",
+        }
+    }
+
+    /// Whether another attempt at the target might succeed, e.g. after
+    /// a slow page load or a dropped connection. Hosts that do not accept
+    /// the connection at all are not tried again.
+    fn is_transient(&self) -> bool {
+        use Error::*;
+        match self {
+            Timeout(_) => true,
+            Navigation(e) => [
+                "ERR_CONNECTION_RESET",
+                "ERR_CONNECTION_CLOSED",
+                "ERR_CONNECTION_ABORTED",
+                "ERR_EMPTY_RESPONSE",
+                "ERR_NETWORK_CHANGED",
+            ]
+            .iter()
+            .any(|code| e.ends_with(code)),
+            _ => false,
+        }
+    }
+}
+
+/// Whether an HTTP status suggests that the server is overloaded or
+/// briefly unavailable, so that another attempt might get the real page.
+/// Other error pages are captured like any other page.
+fn is_transient_status(status: i64) -> bool {
+    matches!(status, 429 | 502 | 503 | 504)
+}
+
+impl Display for Error {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Dns(e) => write!(fmt, "DNS error: {e}"),
+            ConnectionRefused(e) => write!(fmt, "Connection refused: {e}"),
+            Tls(e) => write!(fmt, "TLS error: {e}"),
+            ConnectTimeout(e) | Timeout(e) => write!(fmt, "Timed out: {e}"),
+            Navigation(e) => write!(fmt, "Navigation error: {e}"),
+            Other(e) => write!(fmt, "{e}"),
+        }
+    }
+}
+
+impl<E> From<E> for Error
+where
+    E: Into<color_eyre::Report>,
+{
+    fn from(e: E) -> Self {
+        Error::Other(e.into())
+    }
+}
+
+/// Save the image, returning its path relative to the output directory
 pub fn save(
     target: &Target,
     output_dir: &str,
     png_data: &[u8],
-    report_tx: &mpsc::Sender<ReportMessage>,
-) -> Result<()> {
+) -> Result<String> {
     let filename = format!("{}.png", target_to_filename(target));
 
     let relative_filepath = Path::new("web").join(&filename);
@@ -46,12 +153,66 @@ pub fn save(
     let mut file = File::create(&output_file)?;
     file.write_all(png_data)?;
 
-    let report_message = ReportMessage::Output(ReportMessageContent {
-        mode: Web,
-        target: target.to_string(),
-        output: FileError::File(relative_filepath.display().to_string()),
-    });
-    report_tx.send(report_message)?;
+    Ok(relative_filepath.display().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classify_net_errors() {
+        let test_cases = vec![
+            ("net::ERR_NAME_NOT_RESOLVED", "DNS error"),
+            ("net::ERR_CONNECTION_REFUSED", "Connection refused"),
+            ("net::ERR_CERT_AUTHORITY_INVALID", "TLS error"),
+            ("net::ERR_SSL_PROTOCOL_ERROR", "TLS error"),
+            ("net::ERR_CONNECTION_TIMED_OUT", "Timed out"),
+            ("net::ERR_EMPTY_RESPONSE", "Navigation error"),
+        ];
+
+        for (error_text, category) in test_cases {
+            eprintln!("Test case: {:?}", error_text);
+            let e = Error::from_net_error(error_text.to_string());
+            assert_eq!(e.to_string(), format!("{category}: {error_text}"));
+        }
+    }
+
+    #[test]
+    fn transient_errors() {
+        let test_cases = vec![
+            (Error::from_net_error("net::ERR_TIMED_OUT".into()), true),
+            (
+                Error::from_net_error("net::ERR_CONNECTION_TIMED_OUT".into()),
+                false,
+            ),
+            (
+                Error::from_net_error("net::ERR_CONNECTION_RESET".into()),
+                true,
+            ),
+            (
+                Error::from_net_error("net::ERR_EMPTY_RESPONSE".into()),
+                true,
+            ),
+            (
+                Error::from_net_error("net::ERR_NAME_NOT_RESOLVED".into()),
+                false,
+            ),
+            (
+                Error::from_net_error("net::ERR_CONNECTION_REFUSED".into()),
+                false,
+            ),
+            (Error::from_net_error("net::ERR_CERT_INVALID".into()), false),
+        ];
+
+        for (e, transient) in test_cases {
+            eprintln!("Test case: {}", e);
+            assert_eq!(e.is_transient(), transient);
+        }
 
-    Ok(())
+        for (status, transient) in [(503, true), (429, true), (404, false)] {
+            eprintln!("Test case: {}", status);
+            assert_eq!(is_transient_status(status), transient);
+        }
+    }
 }